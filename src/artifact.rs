@@ -0,0 +1,732 @@
+// artifact module reads Mender artifacts of format version 3, and hands what
+// it finds to an Installer as it goes, so that the payload is streamed from
+// the download to where it is installed without being stored in between.
+//
+// An artifact is a tar archive of:
+//   version          - {"format": "mender", "version": 3}
+//   manifest         - the sha256 checksums of everything below
+//   manifest.sig     - the signature of the manifest, if signed
+//   header.tar.gz    - header-info, the state scripts, and the type-info and
+//                      meta-data of every payload
+//   data/0000.tar.gz - the files of the first payload, and so on
+//
+// The header and the payloads are compressed with gzip, xz or zstd, as told by
+// their suffix (.gz, .xz or .zst), or not at all (no suffix). They are
+// decompressed as they are read.
+use log::{debug, info};
+use openssl::sha::Sha256;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+
+use super::delta;
+
+// The payload type the client installs itself, by writing it to the passive
+// partition. Every other type is installed by an update module.
+pub const ROOTFS_IMAGE: &str = "rootfs-image";
+// A VCDIFF delta against the active partition, which the client applies itself
+// too, writing the result to the passive partition. See delta.
+pub const ROOTFS_DELTA: &str = "rootfs-image-delta";
+
+// The xz and zstd decoders allocate as much memory as the stream asks for, up
+// to these, which is what xz -9 and zstd -19 need. gzip needs 32 KiB at most.
+const XZ_MEMORY_LIMIT: u64 = 128 << 20;
+const ZSTD_WINDOW_LOG_MAX: u32 = 27;
+
+#[derive(Debug)]
+pub enum ArtifactError {
+    Io(io::Error),
+    // The artifact is not one we can read
+    Invalid(String),
+    Checksum(String),
+    // The installer refused the artifact, or failed to install it
+    Install(String),
+}
+
+impl std::fmt::Display for ArtifactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArtifactError::Io(e) => write!(f, "IO error: {}", e),
+            ArtifactError::Invalid(msg) => write!(f, "Invalid artifact: {}", msg),
+            ArtifactError::Checksum(name) => write!(f, "Wrong checksum of {}", name),
+            ArtifactError::Install(msg) => write!(f, "Failed to install the artifact: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ArtifactError {}
+
+impl From<io::Error> for ArtifactError {
+    fn from(error: io::Error) -> Self {
+        ArtifactError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for ArtifactError {
+    fn from(error: serde_json::Error) -> Self {
+        ArtifactError::Invalid(error.to_string())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Payload {
+    pub payload_type: String,
+    // As found in the artifact, for handing on to the update modules
+    pub type_info: Vec<u8>,
+    pub meta_data: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Header {
+    pub artifact_name: String,
+    pub artifact_group: String,
+    pub header_info: Vec<u8>,
+    pub payloads: Vec<Payload>,
+    // The state scripts, by name
+    pub scripts: Vec<(String, Vec<u8>)>,
+}
+
+// Installer is handed the artifact as it is read.
+pub trait Installer {
+    // Called once the header is read, before any payload file.
+    fn header(&mut self, header: &Header) -> Result<(), ArtifactError>;
+    // Called for every file of the payload with the given index, in the
+    // order of the artifact.
+    fn file(&mut self, payload: usize, name: &str, size: u64, content: &mut dyn Read) -> Result<(), ArtifactError>;
+    // Called once all the payload files are in.
+    fn finish(&mut self) -> Result<(), ArtifactError>;
+}
+
+#[derive(Deserialize)]
+struct Version {
+    format: String,
+    version: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct HeaderInfo {
+    payloads: Vec<PayloadInfo>,
+    artifact_provides: Provides,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PayloadInfo {
+    #[serde(rename = "type")]
+    payload_type: String,
+}
+
+// The meta-data of a rootfs-image-delta payload: the base it applies to, which
+// are the first source_size bytes of the active partition, and the result.
+#[derive(Deserialize, Debug)]
+struct DeltaInfo {
+    source_size: u64,
+    source_checksum: String,
+    target_checksum: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Provides {
+    artifact_name: String,
+    artifact_group: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    // Split the name of a tar file of the artifact, i.e. header.tar.xz, into
+    // its name without the suffixes and its compression.
+    fn of(path: &str) -> Result<(&str, Compression), ArtifactError> {
+        let at = match path.rfind(".tar") {
+            Some(at) => at,
+            None => return Err(ArtifactError::Invalid(format!("{} is not a tar file", path))),
+        };
+        let compression = match &path[at + ".tar".len()..] {
+            "" => Compression::None,
+            ".gz" => Compression::Gzip,
+            ".xz" => Compression::Xz,
+            ".zst" => Compression::Zstd,
+            suffix => return Err(ArtifactError::Invalid(format!("Unsupported compression {} of {}", suffix, path))),
+        };
+        Ok((&path[..at], compression))
+    }
+
+    // Decompress what is read from the reader, as it is read.
+    fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Compression::Xz => {
+                let stream = xz2::stream::Stream::new_stream_decoder(XZ_MEMORY_LIMIT, 0)?;
+                Box::new(xz2::read::XzDecoder::new_stream(reader, stream))
+            }
+            Compression::Zstd => {
+                use zstd::stream::raw::{DParameter, Decoder};
+                let mut decoder = Decoder::new()?;
+                decoder.set_parameter(DParameter::WindowLogMax(ZSTD_WINDOW_LOG_MAX))?;
+                Box::new(zstd::stream::zio::Reader::new(BufReader::new(reader), decoder))
+            }
+        })
+    }
+
+    // The suffix of the tar files compressed with it.
+    #[cfg(test)]
+    fn suffix(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Xz => ".xz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    #[cfg(test)]
+    fn compress(self, data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::stream::encode_all(data, 0).unwrap(),
+        }
+    }
+}
+
+// Hashing passes everything read or written through on to the checksum.
+struct Hashing<R> {
+    inner: R,
+    sha: Sha256,
+}
+
+impl<R> Hashing<R> {
+    fn new(inner: R) -> Hashing<R> {
+        Hashing { inner: inner, sha: Sha256::new() }
+    }
+
+    fn checksum(self) -> String {
+        hex::encode(self.sha.finish())
+    }
+}
+
+impl<R: Read> Hashing<R> {
+    // Read what the reader of the content left, and check the checksum.
+    fn verify(mut self, name: &str, manifest: &HashMap<String, String>) -> Result<(), ArtifactError> {
+        io::copy(&mut self, &mut io::sink())?;
+        verify(name, &self.checksum(), manifest)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sha.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.sha.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn verify(name: &str, checksum: &str, manifest: &HashMap<String, String>) -> Result<(), ArtifactError> {
+    match manifest.get(name) {
+        Some(expected) if expected == checksum => Ok(()),
+        Some(_) => Err(ArtifactError::Checksum(name.to_string())),
+        None => Err(ArtifactError::Invalid(format!("{} is not in the manifest", name))),
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.update(data);
+    hex::encode(sha.finish())
+}
+
+// The manifest has a line of "<sha256>  <name>" for every part of the artifact.
+fn parse_manifest(manifest: &str) -> HashMap<String, String> {
+    manifest
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(checksum), Some(name)) => Some((name.to_string(), checksum.to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn parse_header(data: &[u8], compression: Compression) -> Result<Header, ArtifactError> {
+    let mut header = Header::default();
+    let mut archive = tar::Archive::new(compression.decoder(data)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        let parts: Vec<&str> = path.split('/').collect();
+        match parts.as_slice() {
+            ["header-info"] => header.header_info = content,
+            ["scripts", name] => header.scripts.push((name.to_string(), content)),
+            ["headers", index, file] => {
+                let index = payload_index(index)?;
+                if header.payloads.len() <= index {
+                    header.payloads.resize_with(index + 1, Payload::default);
+                }
+                match *file {
+                    "type-info" => header.payloads[index].type_info = content,
+                    "meta-data" => header.payloads[index].meta_data = content,
+                    _ => debug!("Ignoring {} in the header", path),
+                }
+            }
+            _ => debug!("Ignoring {} in the header", path),
+        }
+    }
+    let info: HeaderInfo = serde_json::from_slice(&header.header_info)?;
+    if header.payloads.len() > info.payloads.len() {
+        return Err(ArtifactError::Invalid("More payloads than in header-info".to_string()));
+    }
+    header.payloads.resize_with(info.payloads.len(), Payload::default);
+    for (payload, payload_info) in header.payloads.iter_mut().zip(info.payloads) {
+        payload.payload_type = payload_info.payload_type;
+    }
+    header.artifact_name = info.artifact_provides.artifact_name;
+    header.artifact_group = info.artifact_provides.artifact_group;
+    Ok(header)
+}
+
+// Payloads are numbered 0000, 0001 and so on.
+fn payload_index(name: &str) -> Result<usize, ArtifactError> {
+    name.parse()
+        .map_err(|_| ArtifactError::Invalid(format!("Unknown payload {}", name)))
+}
+
+// Read the artifact, checking everything against the manifest, and hand it
+// to the installer.
+pub fn read<R: Read>(reader: R, installer: &mut dyn Installer) -> Result<(), ArtifactError> {
+    let mut archive = tar::Archive::new(reader);
+    let mut manifest = None;
+    let mut version = None;
+    let mut header = None;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        match path.as_str() {
+            "version" => {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                let parsed: Version = serde_json::from_slice(&data)?;
+                if parsed.format != "mender" || parsed.version != 3 {
+                    return Err(ArtifactError::Invalid(format!(
+                        "Unsupported artifact format {} version {}",
+                        parsed.format, parsed.version
+                    )));
+                }
+                version = Some(sha256(&data));
+            }
+            "manifest" => {
+                let mut data = String::new();
+                entry.read_to_string(&mut data)?;
+                manifest = Some(parse_manifest(&data));
+            }
+            "manifest.sig" => info!("The artifact is signed, but the signature is not verified"),
+            _ if path.starts_with("header.tar") => {
+                let (_, compression) = Compression::of(&path)?;
+                let manifest = manifest.as_ref().ok_or_else(|| missing("manifest"))?;
+                verify("version", &version.take().ok_or_else(|| missing("version"))?, manifest)?;
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                verify(&path, &sha256(&data), manifest)?;
+                let parsed = parse_header(&data, compression)?;
+                debug!("Artifact {} with {} payloads", parsed.artifact_name, parsed.payloads.len());
+                installer.header(&parsed)?;
+                header = Some(parsed);
+            }
+            _ if path.starts_with("data/") => {
+                let header = header.as_ref().ok_or_else(|| missing("header.tar"))?;
+                let manifest = manifest.as_ref().ok_or_else(|| missing("manifest"))?;
+                let (name, compression) = Compression::of(&path["data/".len()..])?;
+                let index = payload_index(name)?;
+                if index >= header.payloads.len() {
+                    return Err(ArtifactError::Invalid(format!("{} is not in the header", path)));
+                }
+                let mut files = tar::Archive::new(compression.decoder(entry)?);
+                for file in files.entries()? {
+                    let file = file?;
+                    let file_name = file.path()?.to_string_lossy().into_owned();
+                    let size = file.header().size()?;
+                    debug!("Installing {} of payload {}", file_name, name);
+                    let mut content = Hashing::new(file);
+                    installer.file(index, &file_name, size, &mut content)?;
+                    content.verify(&format!("data/{}/{}", name, file_name), manifest)?;
+                }
+            }
+            _ => debug!("Ignoring {} in the artifact", path),
+        }
+    }
+    if header.is_none() {
+        return Err(missing("header.tar"));
+    }
+    installer.finish()
+}
+
+fn missing(name: &str) -> ArtifactError {
+    ArtifactError::Invalid(format!("No {} before the payload", name))
+}
+
+// RootfsImage writes the single file of a rootfs-image payload to the
+// target device, or applies the one of a rootfs-image-delta payload to the
+// source device and writes the result to the target.
+pub struct RootfsImage {
+    target: String,
+    source: Option<String>,
+    delta: Option<DeltaInfo>,
+    written: bool,
+}
+
+impl RootfsImage {
+    pub fn new(target: &str) -> RootfsImage {
+        RootfsImage {
+            target: target.to_string(),
+            source: None,
+            delta: None,
+            written: false,
+        }
+    }
+
+    // Accept deltas against the given device, i.e. the active partition.
+    pub fn with_source(mut self, source: &str) -> RootfsImage {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    // Check that the source is what the delta applies to, before anything is
+    // written.
+    fn check_source(&self, payload: &Payload) -> Result<DeltaInfo, ArtifactError> {
+        let source = match self.source {
+            Some(ref source) => source,
+            None => return Err(ArtifactError::Install("Unable to apply a delta without a source".to_string())),
+        };
+        let info: DeltaInfo = serde_json::from_slice(&payload.meta_data)?;
+        info!("Checking that {} is the base of the delta", source);
+        let mut base = Hashing::new(File::open(source)?.take(info.source_size));
+        let size = io::copy(&mut base, &mut io::sink())?;
+        if size != info.source_size || base.checksum() != info.source_checksum {
+            return Err(ArtifactError::Install(format!(
+                "{} is not the base of the delta, install the full image instead",
+                source
+            )));
+        }
+        Ok(info)
+    }
+}
+
+impl Installer for RootfsImage {
+    fn header(&mut self, header: &Header) -> Result<(), ArtifactError> {
+        match header.payloads.as_slice() {
+            [payload] if payload.payload_type == ROOTFS_IMAGE => Ok(()),
+            [payload] if payload.payload_type == ROOTFS_DELTA => {
+                self.delta = Some(self.check_source(payload)?);
+                Ok(())
+            }
+            [payload] => Err(ArtifactError::Install(format!(
+                "Unable to write a {} payload to {}",
+                payload.payload_type, self.target
+            ))),
+            _ => Err(ArtifactError::Install("Expected a single rootfs-image payload".to_string())),
+        }
+    }
+
+    fn file(&mut self, _payload: usize, name: &str, size: u64, content: &mut dyn Read) -> Result<(), ArtifactError> {
+        if self.written {
+            return Err(ArtifactError::Install(format!("More than one image in the payload: {}", name)));
+        }
+        let mut target = OpenOptions::new().write(true).open(&self.target)?;
+        match (&self.delta, &self.source) {
+            (Some(delta), Some(source)) => {
+                info!("Applying {} ({} bytes) to {}, writing to {}", name, size, source, self.target);
+                let mut hashing = Hashing::new(io::BufWriter::new(&mut target));
+                let written = delta::apply(&mut File::open(source)?, content, &mut hashing)?;
+                hashing.flush()?;
+                if hashing.checksum() != delta.target_checksum {
+                    return Err(ArtifactError::Checksum(format!("the {} bytes written to {}", written, self.target)));
+                }
+            }
+            _ => {
+                info!("Writing {} ({} bytes) to {}", name, size, self.target);
+                io::copy(content, &mut target)?;
+            }
+        }
+        target.sync_all()?;
+        self.written = true;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), ArtifactError> {
+        if !self.written {
+            return Err(ArtifactError::Install("No image in the payload".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Build an artifact holding a single payload of the given type, for the tests.
+#[cfg(test)]
+pub fn build(payload_type: &str, files: &[(&str, &[u8])], scripts: &[(&str, &[u8])]) -> Vec<u8> {
+    build_with(Compression::Gzip, payload_type, b"{}", files, scripts)
+}
+
+#[cfg(test)]
+fn build_with(
+    compression: Compression,
+    payload_type: &str,
+    meta_data: &[u8],
+    files: &[(&str, &[u8])],
+    scripts: &[(&str, &[u8])],
+) -> Vec<u8> {
+    fn append(archive: &mut tar::Builder<impl io::Write>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, data).unwrap();
+    }
+
+    let tar = |entries: &[(String, Vec<u8>)]| {
+        let mut archive = tar::Builder::new(Vec::new());
+        for (path, data) in entries {
+            append(&mut archive, path, data);
+        }
+        compression.compress(&archive.into_inner().unwrap())
+    };
+
+    let header_info = format!(
+        r#"{{"payloads": [{{"type": "{}"}}], "artifact_provides": {{"artifact_name": "release-2", "artifact_group": "stable"}}, "artifact_depends": {{"device_type": ["beaglebone"]}}}}"#,
+        payload_type
+    );
+    let mut header = vec![("header-info".to_string(), header_info.into_bytes())];
+    for (name, script) in scripts {
+        header.push((format!("scripts/{}", name), script.to_vec()));
+    }
+    header.push(("headers/0000/type-info".to_string(), format!(r#"{{"type": "{}"}}"#, payload_type).into_bytes()));
+    header.push(("headers/0000/meta-data".to_string(), meta_data.to_vec()));
+    let header = tar(&header);
+    let data = tar(&files.iter().map(|(name, data)| (name.to_string(), data.to_vec())).collect::<Vec<_>>());
+
+    let header_name = format!("header.tar{}", compression.suffix());
+    let version = br#"{"format": "mender", "version": 3}"#;
+    let mut manifest = format!("{}  version\n{}  {}\n", sha256(version), sha256(&header), header_name);
+    for (name, data) in files {
+        manifest.push_str(&format!("{}  data/0000/{}\n", sha256(data), name));
+    }
+    let mut archive = tar::Builder::new(Vec::new());
+    append(&mut archive, "version", version);
+    append(&mut archive, "manifest", manifest.as_bytes());
+    append(&mut archive, &header_name, &header);
+    append(&mut archive, &format!("data/0000.tar{}", compression.suffix()), &data);
+    archive.into_inner().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collect {
+        header: Option<Header>,
+        files: Vec<(usize, String, Vec<u8>)>,
+        finished: bool,
+    }
+
+    impl Installer for Collect {
+        fn header(&mut self, header: &Header) -> Result<(), ArtifactError> {
+            self.header = Some(header.clone());
+            Ok(())
+        }
+
+        fn file(&mut self, payload: usize, name: &str, _size: u64, content: &mut dyn Read) -> Result<(), ArtifactError> {
+            let mut data = Vec::new();
+            content.read_to_end(&mut data)?;
+            self.files.push((payload, name.to_string(), data));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), ArtifactError> {
+            self.finished = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let artifact = build(
+            "single-file",
+            &[("app.conf", b"port=80\n"), ("app", b"#!/bin/sh\n")],
+            &[("ArtifactInstall_Enter_00", b"#!/bin/sh\nexit 0\n")],
+        );
+        let mut collect = Collect::default();
+        read(&artifact[..], &mut collect).unwrap();
+        let header = collect.header.unwrap();
+        assert_eq!(header.artifact_name, "release-2");
+        assert_eq!(header.artifact_group, "stable");
+        assert_eq!(header.scripts[0].0, "ArtifactInstall_Enter_00");
+        assert_eq!(
+            collect.files,
+            vec![
+                (0, "app.conf".to_string(), b"port=80\n".to_vec()),
+                (0, "app".to_string(), b"#!/bin/sh\n".to_vec())
+            ]
+        );
+        assert!(collect.finished);
+    }
+
+    #[test]
+    fn test_wrong_checksum() {
+        let mut artifact = build("rootfs-image", &[("rootfs.ext4", b"rootfs")], &[]);
+        // Corrupt the payload file, but not the compressed stream around it:
+        // swap the manifest checksum of it for the one of something else
+        let manifest = String::from_utf8_lossy(&artifact).into_owned();
+        let checksum = sha256(b"rootfs");
+        let at = manifest.find(&checksum).unwrap();
+        artifact[at..at + 64].copy_from_slice(sha256(b"other").as_bytes());
+        match read(&artifact[..], &mut Collect::default()) {
+            Err(ArtifactError::Checksum(name)) => assert_eq!(name, "data/0000/rootfs.ext4"),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let target = std::env::temp_dir().join(format!("mender-artifact-compression-{}", std::process::id()));
+        let target = target.to_str().unwrap();
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let artifact = build_with(compression, "single-file", b"{}", &[("app", b"app")], &[]);
+            let mut collect = Collect::default();
+            read(&artifact[..], &mut collect).unwrap();
+            assert_eq!(collect.header.unwrap().artifact_name, "release-2");
+            assert_eq!(collect.files, vec![(0, "app".to_string(), b"app".to_vec())]);
+
+            std::fs::write(target, b"").unwrap();
+            let image = vec![0xa5; 1 << 20];
+            let artifact = build_with(compression, "rootfs-image", b"{}", &[("rootfs.ext4", &image)], &[]);
+            read(&artifact[..], &mut RootfsImage::new(target)).unwrap();
+            assert_eq!(std::fs::read(target).unwrap(), image, "{:?}", compression);
+        }
+        std::fs::remove_file(target).unwrap();
+    }
+
+    #[test]
+    fn test_compression_of() {
+        assert_eq!(Compression::of("header.tar").unwrap(), ("header", Compression::None));
+        assert_eq!(Compression::of("0000.tar.gz").unwrap(), ("0000", Compression::Gzip));
+        assert_eq!(Compression::of("0001.tar.xz").unwrap(), ("0001", Compression::Xz));
+        assert_eq!(Compression::of("0002.tar.zst").unwrap(), ("0002", Compression::Zstd));
+        assert!(Compression::of("0000.tar.bz2").is_err());
+        assert!(Compression::of("0000.img").is_err());
+    }
+
+    #[test]
+    fn test_zstd_window_limit() {
+        // A frame asking for a window of 1 GiB, holding an empty raw block
+        let frame: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0xa0, 0x01, 0x00, 0x00];
+        let mut data = Vec::new();
+        assert!(Compression::Zstd.decoder(frame).unwrap().read_to_end(&mut data).is_err());
+        // While one of 1 MiB is fine
+        let frame: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x50, 0x01, 0x00, 0x00];
+        assert_eq!(Compression::Zstd.decoder(frame).unwrap().read_to_end(&mut data).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rootfs_image() {
+        let target = std::env::temp_dir().join(format!("mender-artifact-rootfs-{}", std::process::id()));
+        std::fs::write(&target, b"").unwrap();
+        let target = target.to_str().unwrap();
+        let artifact = build("rootfs-image", &[("rootfs.ext4", b"rootfs")], &[]);
+        read(&artifact[..], &mut RootfsImage::new(target)).unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), b"rootfs");
+
+        let artifact = build("single-file", &[("app", b"app")], &[]);
+        assert!(read(&artifact[..], &mut RootfsImage::new(target)).is_err());
+        std::fs::remove_file(target).unwrap();
+    }
+
+    #[test]
+    fn test_rootfs_delta() {
+        let dir = std::env::temp_dir().join(format!("mender-artifact-delta-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, target) = (dir.join("active"), dir.join("passive"));
+        let (source, target) = (source.to_str().unwrap(), target.to_str().unwrap());
+        // The partition is larger than the image the delta applies to
+        let base = b"release-1 rootfs";
+        std::fs::write(source, [&base[..], b"unused"].concat()).unwrap();
+        let image = b"release-1 rootfs, with a fix";
+        let meta_data = format!(
+            r#"{{"source_size": {}, "source_checksum": "{}", "target_checksum": "{}"}}"#,
+            base.len(),
+            sha256(base),
+            sha256(image)
+        );
+        let delta = delta::build(base, image);
+        let artifact = build_with(
+            Compression::Xz,
+            ROOTFS_DELTA,
+            meta_data.as_bytes(),
+            &[("rootfs.vcdiff", &delta)],
+            &[],
+        );
+        std::fs::write(target, b"").unwrap();
+        read(&artifact[..], &mut RootfsImage::new(target).with_source(source)).unwrap();
+        assert_eq!(std::fs::read(target).unwrap(), image);
+
+        // Not without a source
+        assert!(read(&artifact[..], &mut RootfsImage::new(target)).is_err());
+        // Nor to another base, and nothing is written then
+        std::fs::write(source, b"release-0 rootfs").unwrap();
+        std::fs::write(target, b"").unwrap();
+        match read(&artifact[..], &mut RootfsImage::new(target).with_source(source)) {
+            Err(ArtifactError::Install(msg)) => assert!(msg.contains("not the base"), "{}", msg),
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        assert_eq!(std::fs::read(target).unwrap(), b"");
+        // Nor when the result is not the expected image
+        let meta_data = meta_data.replace(&sha256(image), &sha256(b"other"));
+        let artifact = build_with(
+            Compression::Gzip,
+            ROOTFS_DELTA,
+            meta_data.as_bytes(),
+            &[("rootfs.vcdiff", &delta)],
+            &[],
+        );
+        std::fs::write(source, base).unwrap();
+        match read(&artifact[..], &mut RootfsImage::new(target).with_source(source)) {
+            Err(ArtifactError::Checksum(_)) => {}
+            r => panic!("Unexpected result: {:?}", r.map(|_| ())),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
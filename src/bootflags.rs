@@ -1,74 +1,299 @@
-use log::{debug, info, trace, warn};
-use std::process::{Command, Output};
+use log::debug;
+use std::io::Write;
+use std::process::{Command, Stdio};
 
 use std::collections::HashMap;
 
+use super::partitions::{PartitionError, Partitions};
 
 pub struct BootFlag {
     variables: HashMap<String, String>, // Variables to set
-    flags: Vec<BootFlagType>,
 }
 
 impl BootFlag {
 
     pub fn new() -> BootFlag {
-        BootFlag{variables: HashMap::new(), flags: Vec::new()}
+        BootFlag{variables: HashMap::new()}
     }
 
-    pub fn flag(mut self, key: &str, value: &str) -> Self {
-        self.variables.insert(key.to_string(), value.to_string());
+    pub fn with(mut self, flag: BootFlagType, value: &str) -> Self {
+        self.variables.insert(flag.name().to_string(), value.to_string());
         self
     }
 
-    // TODO -- return error
-    pub fn set(self) -> bool {
-        // Loop all variables in the hashmap, and set them accordingly
-        let mut cmd = Command::new("fw_setenv");
-        for (key, val) in self.variables {
-            cmd.arg(format!("{}={}\n", key, val));
+    // Write all the variables in one go through a fw_setenv script, so that
+    // the environment is never left half updated.
+    pub fn set(self) -> Result<(), &'static str> {
+        let mut script = String::new();
+        for (key, val) in &self.variables {
+            script.push_str(&format!("{} {}\n", key, val));
         }
-        if cmd.status().expect("Failed to execute command").success() {
-            debug!("Successfully set the firmware environment");
-            true
-        } else {
-            info!("Failed to set the firmware environment");
-            false
+        let mut child = Command::new("fw_setenv")
+            .arg("-s")
+            .arg("-")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|_| "Failed to execute fw_setenv")?;
+        // fw_setenv reads the whole script before it writes the environment,
+        // so killing it before stdin is closed keeps a partial script from
+        // being applied
+        let mut stdin = child.stdin.take();
+        let written = match stdin.as_mut() {
+            Some(stdin) => stdin.write_all(script.as_bytes()).is_ok(),
+            None => false,
+        };
+        if !written {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err("Failed to write the fw_setenv script");
+        }
+        drop(stdin);
+        match child.wait() {
+            Ok(status) if status.success() => {
+                debug!("Successfully set the firmware environment");
+                Ok(())
+            }
+            _ => Err("Failed to set the firmware environment"),
         }
     }
 
-    // pub fn get(self, arg: BootFlagType) -> Self {
-    //     self.flags.push(arg);
-    //     self
-    // }
-
-    pub fn get(self, args: Vec<String>) -> HashMap<String, String> {
-        let mut r: HashMap<String, String> = HashMap::new();
-        for arg in args {
-            let bf = BootFlag::fw_printenv(&arg).expect("Failed to get the boot variable");
-            r.insert(arg, bf);
-        }
-        return r;
+    pub fn get_flag(flag: BootFlagType) -> Result<String, &'static str> {
+        BootFlag::fw_printenv(flag.name())
     }
 
     pub fn fw_printenv(name: &str) -> Result<String, &'static str> {
         let mut printenv_cmd = Command::new("fw_printenv");
         printenv_cmd.arg(name);
-        let output = printenv_cmd.output().expect("Failed to get fw value");
-        assert!(output.status.success());
-        return Ok(String::from_utf8(output.stdout).unwrap());
+        let output = match printenv_cmd.output() {
+            Ok(output) => output,
+            Err(_) => return Err("Failed to execute fw_printenv"),
+        };
+        if !output.status.success() {
+            return Err("The boot variable is not set");
+        }
+        match String::from_utf8(output.stdout) {
+            Ok(out) => parse_printenv(name, &out),
+            Err(_) => Err("fw_printenv output is not valid utf-8"),
+        }
     }
+}
 
-    pub fn fw_setenv(key: &str, value: &str) -> bool {
-        let output = Command::new("fw_setenv")
-            .arg(format!("{}={}", key, value))
-            .output()
-            .expect("Failet to set fw key, value pair");
-        return output.status.success();
+// fw_printenv prints 'name=value', strip it down to the value.
+fn parse_printenv(name: &str, output: &str) -> Result<String, &'static str> {
+    let line = output.trim_end_matches('\n');
+    if line.starts_with(name) && line[name.len()..].starts_with('=') {
+        Ok(line[name.len() + 1..].to_string())
+    } else {
+        Err("Unexpected fw_printenv output")
     }
 }
 
+#[derive(Clone, Copy, Debug)]
 pub enum BootFlagType {
     BootCount,
+    BootLimit,
     BootPartition,
+    BootPartitionHex,
     UpgradeAvailable,
+    AltBootCmd,
+}
+
+impl BootFlagType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootFlagType::BootCount => "bootcount",
+            BootFlagType::BootLimit => "bootlimit",
+            BootFlagType::BootPartition => "mender_boot_part",
+            BootFlagType::BootPartitionHex => "mender_boot_part_hex",
+            BootFlagType::UpgradeAvailable => "upgrade_available",
+            BootFlagType::AltBootCmd => "altbootcmd",
+        }
+    }
+}
+
+// BootEnv is the boot setup of the device: the bootloader environment,
+// the two rootfs partitions, and rebooting into them.
+pub trait BootEnv {
+    fn get(&self, flag: BootFlagType) -> Result<String, &'static str>;
+    // Set all the flags at once, or none of them.
+    fn set(&mut self, flags: &[(BootFlagType, String)]) -> Result<(), &'static str>;
+    fn partitions(&self) -> Result<Partitions, PartitionError>;
+    // The device an update can be written to. Never the mounted root.
+    fn write_target(&self, partitions: &Partitions) -> Result<String, PartitionError>;
+    // Returns once the reboot is under way, or an error if it failed to start.
+    fn reboot(&mut self) -> Result<(), &'static str>;
+}
+
+// FwBootEnv is the boot environment of a real device, using the
+// fw_printenv and fw_setenv tools from U-Boot.
+pub struct FwBootEnv {}
+
+impl FwBootEnv {
+    pub fn new() -> FwBootEnv {
+        FwBootEnv {}
+    }
+}
+
+impl BootEnv for FwBootEnv {
+    fn get(&self, flag: BootFlagType) -> Result<String, &'static str> {
+        BootFlag::get_flag(flag)
+    }
+
+    fn set(&mut self, flags: &[(BootFlagType, String)]) -> Result<(), &'static str> {
+        flags
+            .iter()
+            .fold(BootFlag::new(), |bf, (flag, value)| bf.with(*flag, value))
+            .set()
+    }
+
+    fn partitions(&self) -> Result<Partitions, PartitionError> {
+        Partitions::detect()
+    }
+
+    fn write_target(&self, partitions: &Partitions) -> Result<String, PartitionError> {
+        partitions.write_target().map(|target| target.to_string())
+    }
+
+    fn reboot(&mut self) -> Result<(), &'static str> {
+        match Command::new("reboot").status() {
+            Ok(status) if status.success() => Ok(()),
+            _ => Err("Failed to reboot"),
+        }
+    }
+}
+
+// BootState is the part of the bootloader environment which controls
+// which partition is booted, and whether that boot is a trial boot of a
+// new update which will be rolled back if it is not committed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootState {
+    pub boot_part: u32,
+    pub bootcount: u32,
+    pub upgrade_available: bool,
+    pub bootlimit: Option<u32>,
+    pub altbootcmd: bool,
+}
+
+impl BootState {
+    pub fn read<B: BootEnv>(env: &B) -> Result<BootState, &'static str> {
+        let mut vars = HashMap::new();
+        for flag in &[
+            BootFlagType::BootPartition,
+            BootFlagType::BootCount,
+            BootFlagType::UpgradeAvailable,
+        ] {
+            vars.insert(flag.name().to_string(), env.get(*flag)?);
+        }
+        // These are optional, and only tell us how the bootloader rolls back
+        for flag in &[BootFlagType::BootLimit, BootFlagType::AltBootCmd] {
+            if let Ok(value) = env.get(*flag) {
+                vars.insert(flag.name().to_string(), value);
+            }
+        }
+        BootState::from_env(&vars)
+    }
+
+    fn from_env(env: &HashMap<String, String>) -> Result<BootState, &'static str> {
+        let number = |flag: BootFlagType| -> Result<u32, &'static str> {
+            match env.get(flag.name()) {
+                Some(value) => value.trim().parse().map_err(|_| "Boot variable is not a number"),
+                None => Err("Missing boot variable"),
+            }
+        };
+        Ok(BootState {
+            boot_part: number(BootFlagType::BootPartition)?,
+            bootcount: number(BootFlagType::BootCount)?,
+            upgrade_available: number(BootFlagType::UpgradeAvailable)? == 1,
+            bootlimit: number(BootFlagType::BootLimit).ok(),
+            altbootcmd: env.contains_key(BootFlagType::AltBootCmd.name()),
+        })
+    }
+
+    // A trial boot is the first boot into a new update which has not yet
+    // been committed. Rebooting now makes the bootloader roll back.
+    pub fn is_trial_boot(&self) -> bool {
+        self.upgrade_available
+    }
+
+    // Boot the given partition on the next boot, and roll back to the
+    // current one unless the update is committed.
+    pub fn mark_for_trial<B: BootEnv>(&mut self, env: &mut B, partition: u32) -> Result<(), &'static str> {
+        env.set(&[
+            (BootFlagType::BootPartition, partition.to_string()),
+            (BootFlagType::BootPartitionHex, format!("{:x}", partition)),
+            (BootFlagType::UpgradeAvailable, "1".to_string()),
+            (BootFlagType::BootCount, "0".to_string()),
+        ])
+        .map_err(|_| "Failed to mark the partition for a trial boot")?;
+        self.boot_part = partition;
+        self.upgrade_available = true;
+        self.bootcount = 0;
+        Ok(())
+    }
+
+    // Make the currently booted partition permanent.
+    pub fn commit<B: BootEnv>(&mut self, env: &mut B) -> Result<(), &'static str> {
+        env.set(&[
+            (BootFlagType::UpgradeAvailable, "0".to_string()),
+            (BootFlagType::BootCount, "0".to_string()),
+        ])
+        .map_err(|_| "Failed to commit the boot partition")?;
+        self.upgrade_available = false;
+        self.bootcount = 0;
+        Ok(())
+    }
+
+    // Point the bootloader back at the given partition for good.
+    pub fn rollback<B: BootEnv>(&mut self, env: &mut B, partition: u32) -> Result<(), &'static str> {
+        env.set(&[
+            (BootFlagType::BootPartition, partition.to_string()),
+            (BootFlagType::BootPartitionHex, format!("{:x}", partition)),
+            (BootFlagType::UpgradeAvailable, "0".to_string()),
+            (BootFlagType::BootCount, "0".to_string()),
+        ])
+        .map_err(|_| "Failed to roll back the boot partition")?;
+        self.boot_part = partition;
+        self.upgrade_available = false;
+        self.bootcount = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_printenv() {
+        assert_eq!(parse_printenv("bootcount", "bootcount=1\n"), Ok("1".to_string()));
+        assert_eq!(
+            parse_printenv("altbootcmd", "altbootcmd=run mender_altbootcmd; run bootcmd\n"),
+            Ok("run mender_altbootcmd; run bootcmd".to_string())
+        );
+        assert!(parse_printenv("bootcount", "bootlimit=1\n").is_err());
+    }
+
+    #[test]
+    fn test_boot_state_from_env() {
+        let mut env = HashMap::new();
+        env.insert("mender_boot_part".to_string(), "3".to_string());
+        env.insert("bootcount".to_string(), "1".to_string());
+        env.insert("upgrade_available".to_string(), "1".to_string());
+        let state = BootState::from_env(&env).unwrap();
+        assert_eq!(state.boot_part, 3);
+        assert!(state.is_trial_boot());
+        assert_eq!(state.bootlimit, None);
+        assert!(!state.altbootcmd);
+
+        env.insert("upgrade_available".to_string(), "0".to_string());
+        env.insert("bootlimit".to_string(), "1".to_string());
+        env.insert("altbootcmd".to_string(), "run altboot".to_string());
+        let state = BootState::from_env(&env).unwrap();
+        assert!(!state.is_trial_boot());
+        assert_eq!(state.bootlimit, Some(1));
+        assert!(state.altbootcmd);
+
+        env.remove("bootcount");
+        assert!(BootState::from_env(&env).is_err());
+    }
 }
@@ -0,0 +1,444 @@
+// builtin module implements the single-file and directory update modules in
+// the client itself, for devices without a shell to run the stock ones. They
+// answer the update module protocol like the stock modules do, working on the
+// files stored in the file tree during Download:
+//
+// single-file: the file itself, and dest_dir, filename and optionally
+// permissions, owner and group. The file replaces dest_dir/filename
+// atomically, keeping a backup of the old one in the tree for a rollback.
+//
+// directory: update.tar and dest_dir. The tarball is extracted into a
+// staging directory next to dest_dir, which is then swapped with it
+// atomically, keeping the old one next to it until the commit.
+use log::{debug, info};
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use super::modules::ModuleError;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builtin {
+    SingleFile,
+    Directory,
+}
+
+impl Builtin {
+    // The built-in module installing the payload type, if there is one.
+    pub fn find(payload_type: &str) -> Option<Builtin> {
+        match payload_type {
+            "single-file" => Some(Builtin::SingleFile),
+            "directory" => Some(Builtin::Directory),
+            _ => None,
+        }
+    }
+
+    // Handle the state the way the stock module would, and return what it
+    // would have printed.
+    pub fn call(self, state: &str, tree: &Path) -> Result<String, ModuleError> {
+        debug!("Running the built-in {:?} module for {}", self, state);
+        match (self, state) {
+            (_, "NeedsArtifactReboot") => return Ok("No".to_string()),
+            (_, "SupportsRollback") => return Ok("Yes".to_string()),
+            (Builtin::SingleFile, "ArtifactInstall") => SingleFile::read(tree)?.install(tree)?,
+            (Builtin::SingleFile, "ArtifactRollback") => SingleFile::read(tree)?.rollback(tree)?,
+            (Builtin::Directory, "ArtifactInstall") => Directory::read(tree)?.install(tree)?,
+            (Builtin::Directory, "ArtifactCommit") => Directory::read(tree)?.commit()?,
+            (Builtin::Directory, "ArtifactRollback") => Directory::read(tree)?.rollback(tree)?,
+            (Builtin::Directory, "Cleanup") => Directory::read(tree)?.cleanup()?,
+            // Nothing to do in the other states
+            _ => {}
+        }
+        Ok(String::new())
+    }
+}
+
+// Written to the tree once the install starts touching the destination, so
+// that a rollback knows whether there is anything to undo.
+const INSTALLED: &str = "tmp/installed";
+// Written to the tree if the install creates the destination, so that a
+// rollback removes it again.
+const CREATED: &str = "tmp/created";
+
+// Read a parameter of the payload, stored as a file of its own.
+fn parameter(tree: &Path, name: &str) -> Result<Option<String>, ModuleError> {
+    match fs::read_to_string(tree.join("files").join(name)) {
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(ModuleError::Io(e)),
+    }
+}
+
+fn required(tree: &Path, name: &str) -> Result<String, ModuleError> {
+    match parameter(tree, name)? {
+        Some(ref value) if !value.is_empty() => Ok(value.clone()),
+        _ => Err(ModuleError::Payload(format!("No {} in the payload", name))),
+    }
+}
+
+struct SingleFile {
+    filename: String,
+    dest: PathBuf,
+    mode: Option<u32>,
+    owner: Option<u32>,
+    group: Option<u32>,
+}
+
+impl SingleFile {
+    fn read(tree: &Path) -> Result<SingleFile, ModuleError> {
+        let filename = required(tree, "filename")?;
+        if filename.contains('/') {
+            return Err(ModuleError::Payload(format!("Invalid file name {}", filename)));
+        }
+        let mode = match parameter(tree, "permissions")? {
+            Some(mode) => Some(
+                u32::from_str_radix(&mode, 8)
+                    .map_err(|_| ModuleError::Payload(format!("Invalid permissions {}", mode)))?,
+            ),
+            None => None,
+        };
+        let owner = match parameter(tree, "owner")? {
+            Some(owner) => Some(user_id(&owner)?),
+            None => None,
+        };
+        let group = match parameter(tree, "group")? {
+            Some(group) => Some(group_id(&group)?),
+            None => None,
+        };
+        Ok(SingleFile {
+            dest: Path::new(&required(tree, "dest_dir")?).join(&filename),
+            filename: filename,
+            mode: mode,
+            owner: owner,
+            group: group,
+        })
+    }
+
+    fn install(&self, tree: &Path) -> Result<(), ModuleError> {
+        let backup = tree.join("tmp").join("backup");
+        let old = match fs::metadata(&self.dest) {
+            Ok(old) => {
+                copy(&self.dest, &backup, old.mode(), old.uid(), old.gid())?;
+                Some(old)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(self.dest.parent().unwrap())?;
+                None
+            }
+            Err(e) => return Err(ModuleError::Io(e)),
+        };
+        // What is not given in the payload is kept from the file replaced
+        let new = tree.join("files").join(&self.filename);
+        let mode = self
+            .mode
+            .or_else(|| old.as_ref().map(|old| old.mode()))
+            .unwrap_or(fs::metadata(&new)?.mode());
+        let owner = self.owner.or_else(|| old.as_ref().map(|old| old.uid()));
+        let group = self.group.or_else(|| old.as_ref().map(|old| old.gid()));
+        File::create(tree.join(INSTALLED))?;
+        replace(&new, &self.dest, mode, owner, group)?;
+        info!("Installed {:?}", self.dest);
+        Ok(())
+    }
+
+    fn rollback(&self, tree: &Path) -> Result<(), ModuleError> {
+        if !tree.join(INSTALLED).exists() {
+            return Ok(());
+        }
+        let backup = tree.join("tmp").join("backup");
+        match fs::metadata(&backup) {
+            Ok(old) => replace(&backup, &self.dest, old.mode(), Some(old.uid()), Some(old.gid()))?,
+            // There was no file before the update
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => remove_file(&self.dest)?,
+            Err(e) => return Err(ModuleError::Io(e)),
+        }
+        info!("Restored {:?}", self.dest);
+        Ok(())
+    }
+}
+
+// Copy the file next to the destination, and move it over the destination,
+// so that the destination is either the old or the new file, never half of
+// it.
+fn replace(from: &Path, to: &Path, mode: u32, owner: Option<u32>, group: Option<u32>) -> Result<(), ModuleError> {
+    let name = to.file_name().unwrap().to_string_lossy();
+    let tmp = to.with_file_name(format!(".{}.mender-tmp", name));
+    let meta = fs::metadata(from)?;
+    copy(from, &tmp, mode, owner.unwrap_or(meta.uid()), group.unwrap_or(meta.gid()))?;
+    fs::rename(&tmp, to)?;
+    sync_dir(to)
+}
+
+// Copy the file, with the given mode and owner, and make sure it is on disk.
+fn copy(from: &Path, to: &Path, mode: u32, uid: u32, gid: u32) -> Result<(), ModuleError> {
+    fs::copy(from, to)?;
+    chown(to, uid, gid)?;
+    // After the chown, which clears the setuid and setgid bits
+    fs::set_permissions(to, fs::Permissions::from_mode(mode & 0o7777))?;
+    File::open(to)?.sync_all()?;
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> Result<(), ModuleError> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(ModuleError::Io(e)),
+        Ok(()) => sync_dir(path),
+    }
+}
+
+fn remove_dir(path: &Path) -> Result<(), ModuleError> {
+    match fs::remove_dir_all(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(ModuleError::Io(e)),
+        Ok(()) => Ok(()),
+    }
+}
+
+// Make a rename or removal in the directory of the path permanent.
+fn sync_dir(path: &Path) -> Result<(), ModuleError> {
+    File::open(path.parent().unwrap())?.sync_all()?;
+    Ok(())
+}
+
+struct Directory {
+    dest: PathBuf,
+    staging: PathBuf,
+    backup: PathBuf,
+}
+
+impl Directory {
+    fn read(tree: &Path) -> Result<Directory, ModuleError> {
+        let dest = PathBuf::from(required(tree, "dest_dir")?.trim_end_matches('/'));
+        let name = match dest.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(ModuleError::Payload(format!("Invalid destination {:?}", dest))),
+        };
+        // Next to the destination, so that they are on the same file system
+        Ok(Directory {
+            staging: dest.with_file_name(format!(".{}.mender-staging", name)),
+            backup: dest.with_file_name(format!(".{}.mender-backup", name)),
+            dest: dest,
+        })
+    }
+
+    fn install(&self, tree: &Path) -> Result<(), ModuleError> {
+        remove_dir(&self.staging)?;
+        remove_dir(&self.backup)?;
+        fs::create_dir_all(&self.staging)?;
+        // The directory itself keeps the mode and owner it had
+        if let Ok(old) = fs::metadata(&self.dest) {
+            chown(&self.staging, old.uid(), old.gid())?;
+            fs::set_permissions(&self.staging, old.permissions())?;
+        }
+        let mut archive = tar::Archive::new(File::open(tree.join("files").join("update.tar"))?);
+        archive.set_preserve_permissions(true);
+        // Only root may hand the files to their owners
+        archive.set_preserve_ownerships(unsafe { libc::geteuid() } == 0);
+        archive.unpack(&self.staging)?;
+        if self.dest.exists() {
+            // The staging directory holds the old one after the swap
+            exchange(&self.staging, &self.dest)?;
+            fs::rename(&self.staging, &self.backup)?;
+        } else {
+            File::create(tree.join(CREATED))?;
+            fs::create_dir_all(self.dest.parent().unwrap())?;
+            fs::rename(&self.staging, &self.dest)?;
+        }
+        sync_dir(&self.dest)?;
+        info!("Installed {:?}", self.dest);
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<(), ModuleError> {
+        remove_dir(&self.backup)
+    }
+
+    fn rollback(&self, tree: &Path) -> Result<(), ModuleError> {
+        if self.backup.exists() {
+            exchange(&self.backup, &self.dest)?;
+            remove_dir(&self.backup)?;
+        } else if tree.join(CREATED).exists() {
+            remove_dir(&self.dest)?;
+        } else {
+            return Ok(());
+        }
+        sync_dir(&self.dest)?;
+        info!("Restored {:?}", self.dest);
+        Ok(())
+    }
+
+    fn cleanup(&self) -> Result<(), ModuleError> {
+        remove_dir(&self.staging)
+    }
+}
+
+// Swap the two paths in one go. File systems which cannot do that get two
+// renames, with a moment in between where the destination is missing.
+fn exchange(a: &Path, b: &Path) -> Result<(), ModuleError> {
+    let (ca, cb) = (cstring(a)?, cstring(b)?);
+    let exchanged = unsafe {
+        libc::renameat2(libc::AT_FDCWD, ca.as_ptr(), libc::AT_FDCWD, cb.as_ptr(), libc::RENAME_EXCHANGE)
+    };
+    if exchanged == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EINVAL) && e.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(ModuleError::Io(e));
+    }
+    debug!("Unable to exchange {:?} and {:?} atomically: {}", a, b, e);
+    let tmp = a.with_extension("mender-exchange");
+    fs::rename(a, &tmp)?;
+    fs::rename(b, a)?;
+    fs::rename(&tmp, b)?;
+    Ok(())
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn chown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let meta = fs::metadata(path)?;
+    if meta.uid() == uid && meta.gid() == gid {
+        return Ok(());
+    }
+    let path = cstring(path)?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The owner and group are given by name, or by id.
+fn user_id(user: &str) -> Result<u32, ModuleError> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(|_| ModuleError::Payload(format!("Invalid owner {}", user)))?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(ModuleError::Payload(format!("Unknown owner {}", user)));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn group_id(group: &str) -> Result<u32, ModuleError> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| ModuleError::Payload(format!("Invalid group {}", group)))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(ModuleError::Payload(format!("Unknown group {}", group)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact;
+    use crate::modules::UpdateModules;
+
+    fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mender-builtin-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Download the payload into the tree of the built-in module.
+    fn download(dir: &Path, payload_type: &str, files: &[(&str, &[u8])]) -> crate::modules::UpdateModule {
+        let modules = UpdateModules::with_dirs(dir.join("modules"), dir.join("work"));
+        let module = modules.module(payload_type).unwrap();
+        let artifact = artifact::build(payload_type, files, &[]);
+        artifact::read(&artifact[..], &mut module.download()).unwrap();
+        module
+    }
+
+    #[test]
+    fn test_single_file() {
+        let dir = setup("single-file");
+        let dest = dir.join("etc");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("app.conf"), b"port=80\n").unwrap();
+        fs::set_permissions(dest.join("app.conf"), fs::Permissions::from_mode(0o600)).unwrap();
+        let dest_dir = dest.to_str().unwrap().as_bytes();
+        let module = download(
+            &dir,
+            "single-file",
+            &[("app.conf", b"port=8080\n"), ("filename", b"app.conf"), ("dest_dir", dest_dir)],
+        );
+        assert_eq!(module.needs_reboot().unwrap(), crate::modules::Reboot::No);
+        assert!(module.supports_rollback().unwrap());
+        module.call("ArtifactInstall").unwrap();
+        assert_eq!(fs::read(dest.join("app.conf")).unwrap(), b"port=8080\n");
+        // The mode of the replaced file is kept
+        assert_eq!(fs::metadata(dest.join("app.conf")).unwrap().mode() & 0o777, 0o600);
+        module.call("ArtifactRollback").unwrap();
+        assert_eq!(fs::read(dest.join("app.conf")).unwrap(), b"port=80\n");
+        module.cleanup();
+
+        // A new file, with the mode given, is removed again on a rollback
+        let module = download(
+            &dir,
+            "single-file",
+            &[("app", b"binary"), ("filename", b"app"), ("dest_dir", dest_dir), ("permissions", b"755")],
+        );
+        module.call("ArtifactInstall").unwrap();
+        assert_eq!(fs::metadata(dest.join("app")).unwrap().mode() & 0o777, 0o755);
+        module.call("ArtifactRollback").unwrap();
+        assert!(!dest.join("app").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn tarball(files: &[(&str, &[u8], u32)]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(Vec::new());
+        for (path, data, mode) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(*mode);
+            header.set_uid(unsafe { libc::getuid() } as u64);
+            header.set_gid(unsafe { libc::getgid() } as u64);
+            header.set_cksum();
+            archive.append_data(&mut header, path, *data).unwrap();
+        }
+        archive.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = setup("directory");
+        let dest = dir.join("opt").join("app");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("old"), b"old").unwrap();
+        let update = tarball(&[("bin/app", b"binary", 0o755), ("app.conf", b"port=80\n", 0o640)]);
+        let dest_dir = dest.to_str().unwrap().as_bytes();
+        let files: &[(&str, &[u8])] = &[("update.tar", &update), ("dest_dir", dest_dir)];
+
+        let module = download(&dir, "directory", files);
+        module.call("ArtifactInstall").unwrap();
+        assert_eq!(fs::read(dest.join("bin/app")).unwrap(), b"binary");
+        assert_eq!(fs::metadata(dest.join("bin/app")).unwrap().mode() & 0o777, 0o755);
+        assert_eq!(fs::metadata(dest.join("app.conf")).unwrap().mode() & 0o777, 0o640);
+        assert!(!dest.join("old").exists());
+        module.call("ArtifactRollback").unwrap();
+        assert_eq!(fs::read(dest.join("old")).unwrap(), b"old");
+        assert!(!dest.join("bin").exists());
+        module.cleanup();
+
+        let module = download(&dir, "directory", files);
+        module.call("ArtifactInstall").unwrap();
+        module.call("ArtifactCommit").unwrap();
+        module.cleanup();
+        assert!(dest.join("bin/app").exists());
+        // Nothing is left next to the destination
+        assert_eq!(fs::read_dir(dir.join("opt")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+// cli module is the command line interface of the client. 'daemon' runs the
+// state machine, while the other commands are one-shot. check-update and
+// send-inventory are handed to a running daemon if there is one, and
+// otherwise talk to the server themselves. install, commit and rollback are
+// refused while a daemon is running, as they would change the boot
+// environment and the state store under its feet.
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use std::fs;
+
+use super::bootflags::FwBootEnv;
+use super::client::{self, Client, ServerApi};
+use super::clock::SystemClock;
+use super::daemonsocket::{self, DaemonSocket};
+use super::datastore::{self, DataStore};
+use super::modules::UpdateModules;
+use super::scheduler::{self, Scheduler};
+use super::scripts::ScriptRunner;
+use super::signals::Shutdown;
+use super::statemachine::StateMachine;
+use super::updatecontrol::UpdateControl;
+use super::windows::MaintenanceWindows;
+use super::{config, logger, signals, standalone, transitions};
+
+// The name and provides of the running artifact, as name=value lines.
+pub const ARTIFACT_INFO: &str = "/etc/mender/artifact_info";
+
+pub fn app() -> App<'static, 'static> {
+    App::new("mender-rust")
+        .version(crate_version!())
+        .about("Updates the device from a Mender server, or from local artifacts")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .default_value(config::CONFIG_PATH)
+                .help("The configuration file"),
+        )
+        .arg(
+            Arg::with_name("data")
+                .long("data")
+                .short("d")
+                .value_name("DIR")
+                .default_value(datastore::DEFAULT_DATA_DIR)
+                .help("The directory the client keeps its state in"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .short("l")
+                .value_name("LEVEL")
+                .possible_values(&["error", "warn", "info", "debug", "trace"])
+                .default_value("info"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .value_name("FILE")
+                .help("Log to the file instead of to stderr"),
+        )
+        .subcommand(SubCommand::with_name("daemon").about("Run the update daemon"))
+        .subcommand(
+            SubCommand::with_name("install")
+                .about("Install a rootfs-image artifact without a server")
+                .after_help(
+                    "Only rootfs-image artifacts, and deltas of them, are installed without the daemon. \
+                     Update modules and state scripts are not run.",
+                )
+                .arg(
+                    Arg::with_name("artifact")
+                        .required(true)
+                        .help("Path or URL of the artifact"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("commit").about("Commit the installed artifact"))
+        .subcommand(SubCommand::with_name("rollback").about("Roll back the installed artifact"))
+        .subcommand(SubCommand::with_name("check-update").about("Check for an update right away"))
+        .subcommand(SubCommand::with_name("send-inventory").about("Send the inventory right away"))
+        .subcommand(SubCommand::with_name("show-artifact").about("Print the name of the running artifact"))
+        .subcommand(SubCommand::with_name("show-provides").about("Print the provides of the running artifact"))
+        .subcommand(SubCommand::with_name("bootstrap").about("Authorize the device with the server"))
+        .subcommand(SubCommand::with_name("state-graph").about("Print the state machine as a Graphviz graph"))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), String> {
+    config::set_path(matches.value_of("config").unwrap());
+    let level = matches.value_of("log-level").unwrap().parse().unwrap();
+    logger::init(level, matches.value_of("log-file"))?;
+    let data_dir = matches.value_of("data").unwrap();
+    let store = DataStore::new(data_dir);
+    match matches.subcommand() {
+        ("daemon", _) => daemon(data_dir),
+        ("install", Some(m)) => {
+            let artifact = m.value_of("artifact").unwrap();
+            no_daemon("install")?;
+            standalone::install(&mut FwBootEnv::new(), &store, artifact).map_err(|e| e.to_string())
+        }
+        ("commit", _) => {
+            no_daemon("commit")?;
+            standalone::commit(&mut FwBootEnv::new(), &store).map_err(|e| e.to_string())
+        }
+        ("rollback", _) => {
+            no_daemon("rollback")?;
+            if standalone::rollback(&mut FwBootEnv::new(), &store).map_err(|e| e.to_string())? {
+                println!("Reboot to finish the rollback");
+            }
+            Ok(())
+        }
+        ("check-update", _) => check_update(),
+        ("send-inventory", _) => send_inventory(),
+        ("show-artifact", _) => show_artifact(),
+        ("show-provides", _) => show_provides(),
+        ("bootstrap", _) => bootstrap().map(|_| ()),
+        ("state-graph", _) => {
+            print!("{}", transitions::to_dot());
+            Ok(())
+        }
+        (command, _) => Err(format!("Unknown command: {}", command)),
+    }
+}
+
+// Read the parameters of type T from the configuration file.
+fn load<T: DeserializeOwned>() -> Result<T, String> {
+    config::load().map_err(|e| e.to_string())
+}
+
+fn daemon(data_dir: &str) -> Result<(), String> {
+    info!("Starting Mender...");
+    let update_control = UpdateControl::new(load()?);
+    let scheduler = Scheduler::from_conf(load()?).with_store(
+        DataStore::new(data_dir),
+        &[scheduler::CHECK_UPDATE, scheduler::SEND_INVENTORY],
+    );
+    let socket = DaemonSocket::new(update_control.clone(), scheduler.clone(), SystemClock {});
+    if let Err(e) = socket.serve(daemonsocket::DAEMON_SOCKET) {
+        error!("Failed to open the daemon socket: {}", e);
+    }
+    let shutdown = Shutdown::new();
+    if let Err(e) = signals::listen(scheduler.clone(), shutdown.clone()) {
+        error!("Failed to handle signals: {}", e);
+    }
+    // A shutdown wakes up the state machine waiting for the next event, or at
+    // an update control pause
+    let stopped = scheduler.clone();
+    shutdown.on_request(move || stopped.stop());
+    let interrupted = update_control.clone();
+    shutdown.on_request(move || interrupted.interrupt());
+    let windows = MaintenanceWindows::from_conf(load()?)
+        .map_err(|e| format!("Invalid maintenance window configuration: {}", e))?;
+    let mut state_machine = StateMachine::new(
+        Client::new(config::beside(client::SERVER_CERT)).with_cancel(shutdown.cancel()),
+        FwBootEnv::new(),
+        Box::new(scheduler.events(&[scheduler::AUTHORIZE])),
+        Box::new(scheduler.events(&[
+            scheduler::CHECK_UPDATE,
+            scheduler::SEND_INVENTORY,
+            scheduler::CHECK_UPDATE_ONCE,
+        ])),
+        SystemClock {},
+    )
+    .with_store(DataStore::new(data_dir), load()?)
+    .with_scripts(ScriptRunner::new(data_dir).map_err(|e| e.to_string())?)
+    .with_modules(UpdateModules::new(data_dir).map_err(|e| e.to_string())?)
+    .with_windows(windows)
+    .with_update_control(update_control)
+    .with_shutdown(shutdown);
+    state_machine.run().map_err(|e| e.to_string())
+}
+
+// Hand the command to the running daemon. Returns None if there is none.
+fn forward(command: &str) -> Option<Result<(), String>> {
+    match daemonsocket::send(daemonsocket::DAEMON_SOCKET, command) {
+        Ok(ref reply) if reply == "ok" => {
+            info!("Handed {} to the running daemon", command);
+            Some(Ok(()))
+        }
+        Ok(reply) => Some(Err(reply)),
+        Err(_) => None,
+    }
+}
+
+// Fail if a daemon answers on its socket.
+fn no_daemon(command: &str) -> Result<(), String> {
+    match daemonsocket::send(daemonsocket::DAEMON_SOCKET, "metrics") {
+        Ok(_) => Err(format!("The daemon is running, stop it before running {}", command)),
+        Err(_) => Ok(()),
+    }
+}
+
+fn bootstrap() -> Result<Client, String> {
+    let mut client = Client::new(config::beside(client::SERVER_CERT));
+    client.authorize().map_err(|e| e.to_string())?;
+    Ok(client)
+}
+
+fn check_update() -> Result<(), String> {
+    if let Some(result) = forward("check-update") {
+        return result;
+    }
+    match bootstrap()?.check_for_update().map_err(|e| e.to_string())? {
+        Some(update_info) => println!("Update available: {}", update_info.artifact.artifact_name),
+        None => println!("No update available"),
+    }
+    Ok(())
+}
+
+fn send_inventory() -> Result<(), String> {
+    if let Some(result) = forward("send-inventory") {
+        return result;
+    }
+    bootstrap()?.send_inventory().map_err(|e| e.to_string())
+}
+
+fn artifact_info() -> Result<Vec<(String, String)>, String> {
+    fs::read_to_string(ARTIFACT_INFO)
+        .map(|info| parse_artifact_info(&info))
+        .map_err(|e| format!("Failed to read {}: {}", ARTIFACT_INFO, e))
+}
+
+fn parse_artifact_info(info: &str) -> Vec<(String, String)> {
+    info.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => Some((name.trim().to_string(), value.trim().to_string())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn show_artifact() -> Result<(), String> {
+    let (_, name) = artifact_info()?
+        .into_iter()
+        .find(|(name, _)| name == "artifact_name")
+        .ok_or_else(|| format!("No artifact_name in {}", ARTIFACT_INFO))?;
+    println!("{}", name);
+    Ok(())
+}
+
+fn show_provides() -> Result<(), String> {
+    for (name, value) in artifact_info()? {
+        println!("{}={}", name, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_app() {
+        let matches = app()
+            .get_matches_from_safe(vec!["mender-rust", "--data", "/tmp/mender", "install", "release-2.mender"])
+            .unwrap();
+        assert_eq!(matches.value_of("data"), Some("/tmp/mender"));
+        assert_eq!(matches.value_of("config"), Some(config::CONFIG_PATH));
+        match matches.subcommand() {
+            ("install", Some(m)) => assert_eq!(m.value_of("artifact"), Some("release-2.mender")),
+            r => panic!("Unexpected subcommand: {:?}", r),
+        }
+        assert!(app().get_matches_from_safe(vec!["mender-rust"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["mender-rust", "install"]).is_err());
+        assert!(app()
+            .get_matches_from_safe(vec!["mender-rust", "--log-level", "loud", "daemon"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_artifact_info() {
+        let info = "# Written by the build\nartifact_name=release-1\n\nrootfs-image.version = 1.0\nbogus\n";
+        assert_eq!(
+            parse_artifact_info(info),
+            vec![
+                ("artifact_name".to_string(), "release-1".to_string()),
+                ("rootfs-image.version".to_string(), "1.0".to_string()),
+            ]
+        );
+    }
+}
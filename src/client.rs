@@ -1,33 +1,23 @@
 use openssl::rsa::{Padding, Rsa};
-use std::io::BufWriter;
-use std::fs::File;
 use std::io::Read;
 
 use log::{debug, info, trace, warn};
 
+use reqwest::StatusCode;
 use serde::Serialize;
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time;
+use tokio::sync::mpsc;
+
+use super::artifact::{self, ArtifactError, Installer};
+use super::clock::{Clock, SystemClock};
+use super::runtime::{self, Cancel, Interrupted};
+use super::updatecontrol::UpdateControlMap;
 use serde::Deserialize;
 
-#[derive(Serialize)]
-struct IDData {
-    data: String,
-}
-
-impl IDData {
-    fn new() -> IDData {
-        IDData {
-            data: String::from("MAC: 123"),
-        }
-    }
-    fn fill(&mut self) {
-        // TODO -- Only dummy data for now!
-        // self.data
-        //     .insert("MAC".to_string(), "123::345::678".to_string());
-        // self.data
-        //     .insert("serial_number".to_string(), "12345678".to_string());
-    }
-}
-
 // TODO -- This needs to be serialized to bytes (Through serde(?))
 #[derive(Serialize)]
 struct AuthRequestBody {
@@ -48,23 +38,57 @@ pub struct Source {
 // TODO -- What fields are optional here?
 #[derive(Deserialize, Debug, Clone)]
 pub struct Artifact {
-    artifact_name: String,
+    pub artifact_name: String,
     source: Source,
     device_types_compatible: Vec<String>,
-    payload_types: Option<Vec<String>>,
+    pub payload_types: Option<Vec<String>>,
 }
 
 // UpdateInfo holds the information received from a GET /deployments/next
 // HTTP response
 #[derive(Deserialize, Debug, Clone)]
 pub struct UpdateInfo {
-    id: String,
-    artifact: Artifact,
+    pub id: String,
+    pub artifact: Artifact,
+    #[serde(default)]
+    pub update_control_map: Option<UpdateControlMap>,
 }
 
 #[derive(Debug)]
 pub struct ClientError {
-    error: Box<std::error::Error>,
+    error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl ClientError {
+    pub fn new(msg: &str) -> ClientError {
+        ClientError{error: Box::from(msg)}
+    }
+
+    pub fn aborted() -> ClientError {
+        ClientError{error: Box::new(Aborted {})}
+    }
+
+    // The operator aborted the deployment on the server.
+    pub fn is_aborted(&self) -> bool {
+        self.error.is::<Aborted>()
+    }
+}
+
+#[derive(Debug)]
+struct Aborted {}
+
+impl std::fmt::Display for Aborted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The deployment was aborted")
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
 }
 
 impl From<std::io::Error> for ClientError {
@@ -79,33 +103,217 @@ impl From<reqwest::Error> for ClientError {
     }
 }
 
-pub struct Client {
-    pub is_authorized: bool,
+impl From<Interrupted> for ClientError {
+    fn from(error: Interrupted) -> Self {
+        ClientError{error: Box::new(error)}
+    }
+}
+
+impl From<ArtifactError> for ClientError {
+    fn from(error: ArtifactError) -> Self {
+        ClientError{error: Box::new(error)}
+    }
+}
+
+// ServerApi is what the state machine needs from the Mender server.
+pub trait ServerApi {
+    fn is_authorized(&self) -> bool;
+    fn authorize(&mut self) -> Result<(), ClientError>;
+    // Returns None if there is no update for the device.
+    fn check_for_update(&mut self) -> Result<Option<UpdateInfo>, ClientError>;
+    fn send_inventory(&mut self) -> Result<(), ClientError>;
+    // Stream the artifact from the server to the installer, reporting the
+    // download status as it goes. Fails with an aborted error if the
+    // deployment is aborted on the server meanwhile.
+    fn download_update(&mut self, update_info: UpdateInfo, installer: &mut dyn Installer) -> Result<(), ClientError>;
+    fn report_deployment_status(&mut self, deployment_id: &str, status: &str) -> Result<(), ClientError>;
+    // Upload log messages of a failed deployment, for the server to show
+    // along with the failure.
+    fn upload_deployment_log(&mut self, deployment_id: &str, messages: &[String]) -> Result<(), ClientError>;
+}
+
+// How long to wait for the server to answer a request.
+const REQUEST_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// How long to wait for more of the artifact while downloading it.
+const READ_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+// How many chunks of the artifact may be downloaded ahead of writing them.
+const DOWNLOAD_CHUNKS: usize = 4;
+
+// Send the request, and stream the body of the response on the runtime. The
+// body is read from the returned reader as it comes in. Once cancelled, the
+// reader fails.
+pub fn download(request: reqwest::RequestBuilder, cancel: Option<Cancel>) -> Result<BodyReader, ClientError> {
+    let resp = runtime::block_on(runtime::limit(request.send(), REQUEST_TIMEOUT, cancel.clone()))??;
+    if !resp.status().is_success() {
+        let msg = format!("Failed to download {}: {}", resp.url(), resp.status());
+        return Err(ClientError::new(&msg));
+    }
+    let (tx, rx) = mpsc::channel(DOWNLOAD_CHUNKS);
+    runtime::spawn(stream_body(resp, tx, cancel));
+    Ok(BodyReader::new(rx))
+}
+
+// Hand the chunks of the body to the reader, until the body ends, fails, or
+// the reader is dropped.
+async fn stream_body(
+    mut resp: reqwest::Response,
+    mut tx: mpsc::Sender<Result<Vec<u8>, ClientError>>,
+    cancel: Option<Cancel>,
+) {
+    loop {
+        let chunk = match runtime::limit(resp.chunk(), READ_TIMEOUT, cancel.clone()).await {
+            Ok(Ok(Some(chunk))) => Ok(chunk.to_vec()),
+            Ok(Ok(None)) => return,
+            Ok(Err(e)) => Err(ClientError::from(e)),
+            Err(e) => Err(ClientError::from(e)),
+        };
+        let failed = chunk.is_err();
+        if tx.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// BodyReader reads a body streamed by the runtime. Only a few chunks are let
+// in ahead of the reader, so that the download keeps pace with writing it
+// out, rather than piling up in memory.
+pub struct BodyReader {
+    chunks: mpsc::Receiver<Result<Vec<u8>, ClientError>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl BodyReader {
+    fn new(chunks: mpsc::Receiver<Result<Vec<u8>, ClientError>>) -> BodyReader {
+        BodyReader {
+            chunks: chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match futures::executor::block_on(self.chunks.recv()) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
+                None => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// How often to report the status of a deployment while it is being
+// downloaded or installed, so that we notice if it is aborted.
+pub const HEARTBEAT_INTERVAL: time::Duration = time::Duration::from_secs(60);
+
+// Heartbeat wraps a reader, and calls beat at every interval while it is
+// read from. Once beat reports the deployment as aborted, all reads fail.
+struct Heartbeat<R, F> {
+    inner: R,
+    interval: time::Duration,
+    last: time::Instant,
+    beat: F,
+    aborted: bool,
+}
+
+impl<R: Read, F: FnMut() -> Result<(), ClientError>> Heartbeat<R, F> {
+    fn new(inner: R, interval: time::Duration, beat: F) -> Heartbeat<R, F> {
+        Heartbeat {
+            inner: inner,
+            interval: interval,
+            last: time::Instant::now(),
+            beat: beat,
+            aborted: false,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut() -> Result<(), ClientError>> Read for Heartbeat<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.aborted && self.last.elapsed() >= self.interval {
+            self.last = time::Instant::now();
+            match (self.beat)() {
+                Err(ref e) if e.is_aborted() => self.aborted = true,
+                Err(e) => warn!("Failed to send the status heartbeat: {}", e),
+                Ok(()) => trace!("Sent the status heartbeat"),
+            }
+        }
+        if self.aborted {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "The deployment was aborted",
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+// The claims we look at in the JWT token.
+#[derive(Deserialize)]
+struct Claims {
+    exp: Option<u64>,
+}
+
+// When the JWT token expires, if it says.
+fn jwt_expiry(token: &str) -> Option<time::SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    claims.exp.map(|exp| time::UNIX_EPOCH + time::Duration::from_secs(exp))
+}
+
+fn jwt_expired<C: Clock>(expires: Option<time::SystemTime>, clock: &C) -> bool {
+    expires.map_or(false, |expires| clock.wall() >= expires)
+}
+
+pub struct Client<C: Clock = SystemClock> {
+    is_authorized: bool,
     address: String,
     private_key: Rsa<openssl::pkey::Private>,
     // public_key: Rsa<openssl::pkey::Public>,
     tenant_token: Option<String>,
-    pub jwt_token: Option<String>,
+    jwt_token: Option<String>,
+    jwt_expires: Option<time::SystemTime>,
     request_client: reqwest::Client,
+    clock: C,
+    // Cancels the requests which are safe to give up on
+    cancel: Cancel,
+    // Set while the inventory is being sent
+    inventory_pending: Arc<AtomicBool>,
     // Request signature, computed as
     // 'BASE64(SIGN(device_private_key, SHA256(request_body)))'.
     // Verified with the public key presented by the device.
     // signature: String,
 }
 
+// The certificate of the server, next to the configuration file.
+pub const SERVER_CERT: &str = "server.crt";
+
 impl Client {
-    pub fn new() -> Client {
+    pub fn new<P: AsRef<Path>>(server_cert: P) -> Client {
         use std::fs::File;
         use std::io::Read;
         // read the server certificate
         let mut buf = Vec::new();
-        File::open("/etc/mender/server.crt").unwrap().read_to_end(&mut buf).unwrap();
+        File::open(server_cert).unwrap().read_to_end(&mut buf).unwrap();
 
         // create a certificate
         let cert = reqwest::Certificate::from_pem(&buf).unwrap();
 
         let request_client = reqwest::Client::builder()
             .add_root_certificate(cert)
+            .connect_timeout(CONNECT_TIMEOUT)
             .build().unwrap();
         if let Ok(mut file) = File::open("./dummies/private-key-rsa.key") {
             debug!("Reading in the private key...");
@@ -120,7 +328,11 @@ impl Client {
                 private_key: rsa,
                 tenant_token: None,
                 jwt_token: None,
+                jwt_expires: None,
                 request_client: request_client,
+                clock: SystemClock {},
+                cancel: Cancel::new(),
+                inventory_pending: Arc::new(AtomicBool::new(false)),
             }
         } else {
             debug!("Generating rsa private key of length 3072 bits");
@@ -131,15 +343,39 @@ impl Client {
                 private_key: rsa,
                 tenant_token: None,
                 jwt_token: None,
+                jwt_expires: None,
                 request_client: request_client,
+                clock: SystemClock {},
+                cancel: Cancel::new(),
+                inventory_pending: Arc::new(AtomicBool::new(false)),
             }
         }
     }
     fn generate_private_key() -> Rsa<openssl::pkey::Private> {
         Rsa::generate(3072).unwrap()
     }
+}
+
+impl<C: Clock> Client<C> {
+    // Give up on the requests which are safe to give up on, like an update
+    // check, once cancelled.
+    pub fn with_cancel(mut self, cancel: Cancel) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // Make the request on the runtime, giving up on it after REQUEST_TIMEOUT,
+    // or if it is cancellable, once cancelled.
+    fn run<F, T>(&self, request: F, cancellable: bool) -> Result<T, ClientError>
+    where
+        F: Future<Output = Result<T, ClientError>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let cancel = if cancellable { Some(self.cancel.clone()) } else { None };
+        runtime::block_on(runtime::limit(request, REQUEST_TIMEOUT, cancel))?
+    }
 
-    pub fn authorize(&self) -> Result<reqwest::Response, ClientError> {
+    fn auth_request(&self) -> reqwest::RequestBuilder {
         debug!("The client is trying to authorize...");
         // Do authorization
         // Authorization API can be found at:
@@ -166,13 +402,12 @@ impl Client {
         // Base64 encode the signature
         let sig_base64 = base64::encode(&sig[..384]);
 
-        Ok(self.request_client
+        self.request_client
             .post(&uri)
             .header("Content-Type", "application/json")
             .header("X-MEN-Signature", sig_base64)
             .body(auth_req_str)
             // .body(auth_req_str.as_bytes())
-            .send()?)
     }
 
     fn sign_request(&self, request: &[u8]) -> [u8; 3072] {
@@ -187,8 +422,7 @@ impl Client {
             .expect("Failed to set the signer padding");
         signer.update(request).expect("Failed to sign the request");
         let mut sig = [0; 3072];
-        let len = signer.sign(&mut sig).expect("Failed to sign the payload");
-        println!("encypted length: {}", len);
+        signer.sign(&mut sig).expect("Failed to sign the payload");
         sig
     }
 
@@ -204,9 +438,8 @@ impl Client {
     // Schemes : HTTPS
     // Paths
     // PATCH /device/attributes
-    pub fn send_inventory(&self) -> Result<reqwest::Response, reqwest::Error> {
+    fn inventory_request(&self) -> reqwest::RequestBuilder {
         debug!("Client: Sending inventory...");
-        let request_client = reqwest::Client::new();
         self.request_client
             .patch("https://docker.mender.io/api/devices/v1/inventory/device/attributes")
             .bearer_auth(self.jwt_token.as_ref().unwrap())
@@ -224,49 +457,202 @@ impl Client {
                     value: "foobar".to_string(),
                 },
             ])
-            .send()
     }
 
     // Host : docker.mender.io
     // BasePath : /api/devices/v1/deployments
     // Schemes : HTTPS
     // GET /device/deployments/next
-    pub fn check_for_update(&self) -> Result<reqwest::Response, reqwest::Error> {
+    fn update_check_request(&self) -> reqwest::RequestBuilder {
         debug!("Client: Checking for update...");
         self.request_client
             .get("https://docker.mender.io/api/devices/v1/deployments/device/deployments/next")
             .bearer_auth(self.jwt_token.as_ref().unwrap())
             .query(&[("device_type", "qemux86-64"), ("artifact_name", "foobar")])
-            .send()
     }
 
-    pub fn download_update(&self, update_info: UpdateInfo) {
-        debug!("Client: Downloading the update...");
-        let request_client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .build()
-            .unwrap();
-        let mut resp = request_client
-            .get(&update_info.artifact.source.uri)
-            .send().expect("Failed to GET the update");
-        // TODO -- Later this should be streamed directly to the artifact.
-        // let mut f = std::fs::File::create("artifact.mender").expect("Failed to create file");
-        let mut buf: Vec<u8> = Vec::new();
-        // let mut buf = BufWriter::new(buf);
-        // resp.copy_to(&mut buf).expect("Failed to write the Artifact to file");
-        // debug!("# Bytes in buffer: {}", buf_writer.buffer().len());
+    // Host : docker.mender.io
+    // BasePath : /api/devices/v1/deployments
+    // Schemes : HTTPS
+    // PUT /device/deployments/{id}/status
+    fn status_request(&self, deployment_id: &str, status: &str) -> reqwest::RequestBuilder {
+        debug!("Client: Reporting deployment status: {}", status);
+        let uri = format!(
+            "https://docker.mender.io/api/devices/v1/deployments/device/deployments/{}/status",
+            deployment_id
+        );
+        self.request_client
+            .put(&uri)
+            .bearer_auth(self.jwt_token.as_ref().unwrap())
+            .json(&DeploymentStatus {
+                status: status.to_string(),
+            })
+    }
 
-        debug!("Ready to parse the Artifact");
-        let mut ma = mender_artifact::MenderArtifact::new(&mut resp);
-        let mut payloads = ma.parse("/dev/hda3"); // TODO -- make dynamic
+    // Host : docker.mender.io
+    // BasePath : /api/devices/v1/deployments
+    // Schemes : HTTPS
+    // PUT /device/deployments/{id}/log
+    fn log_request(&self, deployment_id: &str, messages: &[String]) -> reqwest::RequestBuilder {
+        debug!("Client: Uploading the deployment log");
+        let uri = format!(
+            "https://docker.mender.io/api/devices/v1/deployments/device/deployments/{}/log",
+            deployment_id
+        );
+        let timestamp = chrono::DateTime::<chrono::Utc>::from(self.clock.wall()).to_rfc3339();
+        self.request_client
+            .put(&uri)
+            .bearer_auth(self.jwt_token.as_ref().unwrap())
+            .json(&DeploymentLog {
+                messages: messages
+                    .iter()
+                    .map(|message| LogMessage {
+                        timestamp: timestamp.clone(),
+                        level: "error".to_string(),
+                        message: message.clone(),
+                    })
+                    .collect(),
+            })
+    }
 
+    // The status of a deployment is reported even while shutting down.
+    fn report_status(&self, deployment_id: &str, status: &str) -> Result<(), ClientError> {
+        let request = self.status_request(deployment_id, status);
+        self.run(
+            async move {
+                let resp = request.send().await?;
+                match resp.status() {
+                    StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+                    StatusCode::CONFLICT => Err(ClientError::aborted()),
+                    status => Err(ClientError::new(&format!("Unexpected status code: {}", status))),
+                }
+            },
+            false,
+        )
     }
 }
 
-impl Client {
-    pub fn is_authorized(&self) -> bool {
-        return self.is_authorized;
+impl<C: Clock> ServerApi for Client<C> {
+    // Once the JWT token expires we need to authorize again.
+    fn is_authorized(&self) -> bool {
+        self.is_authorized && !jwt_expired(self.jwt_expires, &self.clock)
+    }
+
+    // Try to authorize, and keep the JWT token for the following requests.
+    fn authorize(&mut self) -> Result<(), ClientError> {
+        let request = self.auth_request();
+        let jwt = self.run(
+            async move {
+                let resp = request.send().await?;
+                match resp.status() {
+                    StatusCode::OK => Ok(resp.text().await?),
+                    _ => Err(ClientError::new(&format!("Failed to authorize the client: {:?}", resp))),
+                }
+            },
+            true,
+        )?;
+        info!("Client successfully authorized with the Mender server");
+        self.jwt_expires = jwt_expiry(&jwt);
+        debug!("The JWT token expires at {:?}", self.jwt_expires);
+        self.jwt_token = Some(jwt);
+        self.is_authorized = true;
+        Ok(())
+    }
+
+    fn check_for_update(&mut self) -> Result<Option<UpdateInfo>, ClientError> {
+        let request = self.update_check_request();
+        self.run(
+            async move {
+                let resp = request.send().await?;
+                debug!("Sync: UpdateCheck: Received response");
+                match resp.status() {
+                    StatusCode::OK => {
+                        let update_info: UpdateInfo = resp.json().await?;
+                        debug!("Successfully deserialized the update response");
+                        Ok(Some(update_info))
+                    }
+                    StatusCode::NO_CONTENT => Ok(None),
+                    status => Err(ClientError::new(&format!("Unexpected status code: {}", status))),
+                }
+            },
+            true,
+        )
     }
+
+    // The inventory is sent in the background, so that it does not hold up
+    // the status reports of a deployment. One at a time, though.
+    fn send_inventory(&mut self) -> Result<(), ClientError> {
+        if self.inventory_pending.swap(true, Ordering::SeqCst) {
+            debug!("Client: Still sending the previous inventory");
+            return Ok(());
+        }
+        let request = self.inventory_request();
+        let pending = self.inventory_pending.clone();
+        let cancel = self.cancel.clone();
+        runtime::spawn(async move {
+            match runtime::limit(request.send(), REQUEST_TIMEOUT, Some(cancel)).await {
+                Ok(Ok(ref resp)) if resp.status() == StatusCode::OK => debug!("Client: Inventory sent"),
+                Ok(Ok(resp)) => warn!("Client: Failed to send the inventory: {}", resp.status()),
+                Ok(Err(e)) => warn!("Client: Failed to send the inventory: {}", e),
+                Err(e) => warn!("Client: Failed to send the inventory: {}", e),
+            }
+            pending.store(false, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    fn download_update(&mut self, update_info: UpdateInfo, installer: &mut dyn Installer) -> Result<(), ClientError> {
+        debug!("Client: Downloading the update...");
+        let body = download(
+            self.request_client.get(&update_info.artifact.source.uri),
+            Some(self.cancel.clone()),
+        )?;
+        let client = &*self;
+        let mut reader = Heartbeat::new(body, HEARTBEAT_INTERVAL, || {
+            client.report_status(&update_info.id, "downloading")
+        });
+        let installed = artifact::read(&mut reader, installer);
+        if reader.aborted {
+            info!("Client: The deployment was aborted, stopping the download");
+            return Err(ClientError::aborted());
+        }
+        Ok(installed?)
+    }
+
+    fn report_deployment_status(&mut self, deployment_id: &str, status: &str) -> Result<(), ClientError> {
+        self.report_status(deployment_id, status)
+    }
+
+    fn upload_deployment_log(&mut self, deployment_id: &str, messages: &[String]) -> Result<(), ClientError> {
+        let request = self.log_request(deployment_id, messages);
+        self.run(
+            async move {
+                let resp = request.send().await?;
+                match resp.status() {
+                    StatusCode::OK | StatusCode::NO_CONTENT => Ok(()),
+                    status => Err(ClientError::new(&format!("Unexpected status code: {}", status))),
+                }
+            },
+            false,
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct DeploymentStatus {
+    status: String,
+}
+
+#[derive(Serialize)]
+struct DeploymentLog {
+    messages: Vec<LogMessage>,
+}
+
+#[derive(Serialize)]
+struct LogMessage {
+    timestamp: String,
+    level: String,
+    message: String,
 }
 
 #[derive(Serialize)]
@@ -280,19 +666,38 @@ struct InventoryAttribute {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::VirtualClock;
+    use crate::config;
     #[test]
     fn test_authorization() {
-        let client = Client::new();
+        let client = Client::new(config::beside(SERVER_CERT));
         // assert_eq!(client.authorize(), true);
     }
 
+    #[test]
+    fn test_jwt_expiry() {
+        // The header is {"alg":"RS256","typ":"JWT"}
+        let payload = base64::encode_config(r#"{"sub":"device","exp":1583380800}"#, base64::URL_SAFE_NO_PAD);
+        let token = format!("eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.{}.c2lnbmF0dXJl", payload);
+        let expires = jwt_expiry(&token);
+        assert_eq!(expires, Some(time::UNIX_EPOCH + time::Duration::from_secs(1583380800)));
+        assert_eq!(jwt_expiry("not a token"), None);
+
+        let clock = VirtualClock::at(time::UNIX_EPOCH + time::Duration::from_secs(1583380800 - 3600));
+        assert!(!jwt_expired(expires, &clock));
+        clock.advance(time::Duration::from_secs(3600));
+        assert!(jwt_expired(expires, &clock));
+        // A token without an expiry never expires
+        assert!(!jwt_expired(None, &clock));
+    }
+
     #[test]
     fn test_sha256sum() {
         let expected_res = [
             195, 171, 143, 241, 55, 32, 232, 173, 144, 71, 221, 57, 70, 107, 60, 137, 116, 229,
             146, 194, 250, 56, 61, 74, 57, 96, 113, 76, 174, 240, 196, 242,
         ];
-        let client = Client::new();
+        let client = Client::new(config::beside(SERVER_CERT));
         let hash = client.shasum256_request("foobar".as_bytes());
         assert_eq!(hash, expected_res);
     }
@@ -322,7 +727,7 @@ mod tests {
             107, 71, 219, 230, 188, 184, 38, 62, 91, 124, 81, 163, 211, 37, 162, 87, 19, 23, 214,
             102,
         ];
-        let client = Client::new();
+        let client = Client::new(config::beside(SERVER_CERT));
         let res = client.sign_request("foobar".as_bytes());
         assert_eq!(res[..384], expected_res[..384]);
         // openssl::rsa::Rsa::dec
@@ -335,4 +740,55 @@ mod tests {
         let parsed_resp: UpdateInfo = serde_json::from_str(resp_str).expect("Failed to parse update info...");
 
     }
+
+    #[test]
+    fn test_body_reader() {
+        let (mut tx, rx) = mpsc::channel(DOWNLOAD_CHUNKS);
+        futures::executor::block_on(async {
+            tx.send(Ok(b"foo".to_vec())).await.unwrap();
+            tx.send(Ok(Vec::new())).await.unwrap();
+            tx.send(Ok(b"bar".to_vec())).await.unwrap();
+        });
+        drop(tx);
+        let mut buf = Vec::new();
+        BodyReader::new(rx).read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foobar");
+
+        let (mut tx, rx) = mpsc::channel(DOWNLOAD_CHUNKS);
+        futures::executor::block_on(async {
+            tx.send(Ok(b"foo".to_vec())).await.unwrap();
+            tx.send(Err(ClientError::from(Interrupted::TimedOut))).await.unwrap();
+        });
+        let mut reader = BodyReader::new(rx);
+        let mut buf = [0; 2];
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(reader.read(&mut buf).unwrap_err().to_string(), "Timed out");
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let mut beats = 0;
+        let mut reader = Heartbeat::new(&b"foobar"[..], time::Duration::from_secs(0), || {
+            beats += 1;
+            Ok(())
+        });
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foobar");
+        assert!(!reader.aborted);
+        assert!(beats > 0);
+
+        let mut reader = Heartbeat::new(&b"foobar"[..], time::Duration::from_secs(0), || {
+            Err(ClientError::aborted())
+        });
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(reader.aborted);
+
+        let mut reader = Heartbeat::new(&b"foobar"[..], time::Duration::from_secs(0), || {
+            Err(ClientError::new("Connection refused"))
+        });
+        assert!(reader.read_to_end(&mut Vec::new()).is_ok());
+        assert!(!reader.aborted);
+    }
 }
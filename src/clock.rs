@@ -0,0 +1,134 @@
+// clock module abstracts away the passing of time, so that the state machine,
+// the scheduler and everything else which waits can be driven by a virtual
+// clock in tests.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Condvar, MutexGuard};
+use std::thread;
+use std::time;
+
+// A future waiting on the async runtime.
+pub type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock {
+    // Monotonic time, for measuring intervals.
+    fn now(&self) -> time::Instant;
+    // The time of day, which unlike now() may jump.
+    fn wall(&self) -> time::SystemTime;
+    fn sleep(&self, duration: time::Duration);
+
+    fn sleep_until(&self, deadline: time::Instant) {
+        let now = self.now();
+        if deadline > now {
+            self.sleep(deadline - now);
+        }
+    }
+
+    // Wait on the condition variable until it is notified, or until the
+    // deadline. May return early, so check the deadline again.
+    fn wait_until<'a, T>(&self, cvar: &Condvar, guard: MutexGuard<'a, T>, deadline: time::Instant) -> MutexGuard<'a, T>
+    where
+        Self: Sized;
+
+    // Wait on the async runtime until the deadline.
+    fn delay_until(&self, deadline: time::Instant) -> Delay;
+}
+
+#[derive(Clone, Copy)]
+pub struct SystemClock {}
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+
+    fn wall(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        thread::sleep(duration)
+    }
+
+    fn wait_until<'a, T>(&self, cvar: &Condvar, guard: MutexGuard<'a, T>, deadline: time::Instant) -> MutexGuard<'a, T> {
+        let timeout = deadline.saturating_duration_since(time::Instant::now());
+        cvar.wait_timeout(guard, timeout).unwrap().0
+    }
+
+    fn delay_until(&self, deadline: time::Instant) -> Delay {
+        Box::pin(tokio::time::delay_until(deadline.into()))
+    }
+}
+
+// VirtualClock only moves when it is advanced. Sleeping on it advances it at
+// once, so that a test never really waits, while a test driving another
+// thread advances it by hand.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct VirtualClock(std::sync::Arc<std::sync::Mutex<(time::Instant, time::SystemTime)>>);
+
+#[cfg(test)]
+impl VirtualClock {
+    pub fn new() -> VirtualClock {
+        VirtualClock::at(time::SystemTime::now())
+    }
+
+    pub fn at(wall: time::SystemTime) -> VirtualClock {
+        VirtualClock(std::sync::Arc::new(std::sync::Mutex::new((time::Instant::now(), wall))))
+    }
+
+    pub fn advance(&self, duration: time::Duration) {
+        let mut t = self.0.lock().unwrap();
+        t.0 += duration;
+        t.1 += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for VirtualClock {
+    fn now(&self) -> time::Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn wall(&self) -> time::SystemTime {
+        self.0.lock().unwrap().1
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        self.advance(duration)
+    }
+
+    // Nothing notifies us when the clock is advanced, so look again shortly.
+    fn wait_until<'a, T>(&self, cvar: &Condvar, guard: MutexGuard<'a, T>, deadline: time::Instant) -> MutexGuard<'a, T> {
+        if self.now() >= deadline {
+            return guard;
+        }
+        cvar.wait_timeout(guard, time::Duration::from_millis(1)).unwrap().0
+    }
+
+    fn delay_until(&self, deadline: time::Instant) -> Delay {
+        let clock = self.clone();
+        Box::pin(async move {
+            while clock.now() < deadline {
+                tokio::time::delay_for(time::Duration::from_millis(1)).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let (start, wall) = (clock.now(), clock.wall());
+        clock.sleep_until(start + time::Duration::from_secs(3600));
+        assert_eq!(clock.now() - start, time::Duration::from_secs(3600));
+        assert_eq!(clock.wall().duration_since(wall).unwrap(), time::Duration::from_secs(3600));
+        // In the past, so no sleeping
+        clock.sleep_until(start);
+        assert_eq!(clock.now() - start, time::Duration::from_secs(3600));
+    }
+}
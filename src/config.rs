@@ -0,0 +1,52 @@
+// config module reads the client configuration, by default from
+// /etc/mender/mender.conf. Every module deserializes the parameters it cares
+// about into its own struct, so this only knows where the file lives and how
+// to read it.
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub const CONFIG_PATH: &str = "/etc/mender/mender.conf";
+
+lazy_static! {
+    static ref PATH: RwLock<String> = RwLock::new(CONFIG_PATH.to_string());
+}
+
+// Read the configuration from the given file from now on.
+pub fn set_path(path: &str) {
+    *PATH.write().unwrap() = path.to_string();
+}
+
+// The file of the given name next to the configuration file, like the
+// server certificate.
+pub fn beside(name: &str) -> PathBuf {
+    Path::new(&*PATH.read().unwrap()).with_file_name(name)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // The configuration file could not be read
+    Io(String, std::io::Error),
+    // The configuration file is not what the parameters should look like
+    Parse(String, serde_json::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Error opening {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}: {}", path, e),
+        }
+    }
+}
+
+// Read the parameters of type T from the configuration file.
+pub fn load<T: DeserializeOwned>() -> Result<T, ConfigError> {
+    let path = PATH.read().unwrap().clone();
+    let file = File::open(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).map_err(|e| ConfigError::Parse(path, e))
+}
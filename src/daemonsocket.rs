@@ -0,0 +1,172 @@
+// daemonsocket module serves the socket of a running daemon. The one-shot
+// commands of the CLI use it to have the daemon check for an update or send
+// its inventory right away, and applications on the device use it for update
+// control. 'check-update <seconds>' has it check for an update once, that
+// long from now, and 'reschedule <job> <seconds>' changes how often it checks
+// for an update or sends its inventory. 'metrics' returns the metrics of the
+// event queue, as JSON. Every connection sends a single command line, and gets
+// a single line back.
+use log::{debug, warn};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time;
+
+use super::clock::Clock;
+use super::scheduler::{self, Scheduler};
+use super::updatecontrol::UpdateControl;
+
+pub const DAEMON_SOCKET: &str = "/run/mender/daemon.sock";
+
+// How long to wait for the command of a connection, so that one which never
+// sends it does not keep the others from being served.
+const READ_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+pub struct DaemonSocket<C> {
+    update_control: UpdateControl,
+    scheduler: Scheduler,
+    clock: C,
+}
+
+impl<C: Clock + Send + 'static> DaemonSocket<C> {
+    pub fn new(update_control: UpdateControl, scheduler: Scheduler, clock: C) -> DaemonSocket<C> {
+        DaemonSocket {
+            update_control: update_control,
+            scheduler: scheduler,
+            clock: clock,
+        }
+    }
+
+    fn command(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            ["check-update"] => self.scheduler.trigger(scheduler::CHECK_UPDATE),
+            ["send-inventory"] => self.scheduler.trigger(scheduler::SEND_INVENTORY),
+            ["check-update", secs] => {
+                seconds(secs).and_then(|after| self.scheduler.reschedule(scheduler::CHECK_UPDATE_ONCE, after))
+            }
+            ["reschedule", job @ "check-update", secs] | ["reschedule", job @ "send-inventory", secs] => {
+                seconds(secs).and_then(|interval| self.scheduler.reschedule(job, interval))
+            }
+            ["metrics"] => return serde_json::to_string(&self.scheduler.metrics()).unwrap(),
+            _ => return self.update_control.command(line, &self.clock),
+        };
+        match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    // Serve the socket from a background thread.
+    pub fn serve<P: AsRef<Path>>(self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Left behind by an earlier run
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        debug!("Listening on the daemon socket {:?}", path);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.handle(stream) {
+                            warn!("Daemon socket: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Daemon socket: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(&self, stream: UnixStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let mut stream = stream;
+        writeln!(stream, "{}", self.command(&line))
+    }
+}
+
+fn seconds(secs: &str) -> Result<time::Duration, &'static str> {
+    secs.parse().map(time::Duration::from_secs).map_err(|_| "Invalid number of seconds")
+}
+
+// Send a command to the running daemon, and return its reply. Fails if no
+// daemon is listening.
+pub fn send<P: AsRef<Path>>(path: P, command: &str) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::eventqueue::QueueMetrics;
+    use crate::scheduler::IntervalConf;
+    use crate::updatecontrol::{ControlConf, ControlState};
+    use crate::{Event, EventProducer};
+
+    #[test]
+    fn test_socket() {
+        let path = std::env::temp_dir().join(format!("mender-daemon-{}.sock", std::process::id()));
+        let update_control = UpdateControl::new(ControlConf::default());
+        let scheduler = Scheduler::from_conf(IntervalConf::default());
+        let events = scheduler.events(&[
+            scheduler::CHECK_UPDATE,
+            scheduler::SEND_INVENTORY,
+            scheduler::CHECK_UPDATE_ONCE,
+        ]);
+        let clock = VirtualClock::at(time::UNIX_EPOCH + time::Duration::from_secs(1_000_000));
+        DaemonSocket::new(update_control.clone(), scheduler, clock).serve(&path).unwrap();
+        events.start();
+        // The inventory is sent as soon as the jobs start
+        match events.next() {
+            Event::SendInventory => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        assert_eq!(send(&path, "check-update").unwrap(), "ok");
+        assert_eq!(send(&path, "send-inventory").unwrap(), "ok");
+        match (events.next(), events.next()) {
+            (Event::CheckForUpdate, Event::SendInventory) => {}
+            r => panic!("Unexpected events: {:?}", r),
+        }
+        let metrics: QueueMetrics = serde_json::from_str(&send(&path, "metrics").unwrap()).unwrap();
+        assert_eq!(metrics.delivered, 3);
+
+        assert_eq!(send(&path, "reschedule send-inventory 60").unwrap(), "ok");
+        assert_eq!(send(&path, "reschedule authorize 60").unwrap(), "error: Unknown command");
+        assert_eq!(send(&path, "check-update soon").unwrap(), "error: Invalid number of seconds");
+        assert_eq!(send(&path, "check-update 0").unwrap(), "ok");
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+
+        let map = r#"{"id": "ae8b5c4e", "states": {"ArtifactInstall_Enter": {"action": "pause"}}}"#;
+        assert_eq!(send(&path, &format!("set {}", map)).unwrap(), "ok");
+        let state: ControlState = serde_json::from_str(&send(&path, "status").unwrap()).unwrap();
+        assert_eq!(state, update_control.state());
+        assert!(state.map.is_some());
+        // Expiring by the clock of the daemon
+        assert_eq!(state.expires, Some(1_000_000 + 3600));
+        assert_eq!(send(&path, "continue").unwrap(), "error: The deployment is not paused");
+        assert_eq!(send(&path, "set {").unwrap(), "error: Invalid update control map");
+        assert_eq!(send(&path, "bogus").unwrap(), "error: Unknown command");
+        // A connection which never sends its command is given up on
+        let _idle = UnixStream::connect(&path).unwrap();
+        assert!(!send(&path, "metrics").unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+// datastore module persists the state of an in-progress deployment to the
+// data directory, so that the client can pick up where it left off after a
+// reboot or a crash.
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use super::client::UpdateInfo;
+use super::updatecontrol::ControlState;
+use super::ExternalState;
+
+pub const DEFAULT_DATA_DIR: &str = "/var/lib/mender";
+const STATE_FILE: &str = "mender-state.json";
+// When the periodic jobs of the scheduler last ran
+const RUNS_FILE: &str = "mender-runs.json";
+
+// Bump this whenever StateData changes in a way older clients can not read.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct StoreConf {
+    // The maximum number of state transitions a single deployment may go
+    // through before we assume it is stuck in a loop.
+    #[serde(rename = "StateDataStoreCountMax")]
+    pub max_state_count: u32,
+}
+
+impl Default for StoreConf {
+    fn default() -> Self {
+        StoreConf {
+            max_state_count: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StateData {
+    pub version: u32,
+    pub state: ExternalState,
+    pub deployment_id: String,
+    pub artifact_name: String,
+    pub payload_types: Vec<String>,
+    // The number of times the deployment has been resumed after a reboot or crash
+    pub retries: u32,
+    // The number of states the deployment has entered
+    #[serde(default)]
+    pub state_count: u32,
+    // Set once the deployment has been forced onto the failure path
+    #[serde(default)]
+    pub loop_detected: bool,
+    // Set while the deployment is held back, waiting for a maintenance window
+    #[serde(default)]
+    pub paused: bool,
+    #[serde(default)]
+    pub update_control: ControlState,
+    // Installed with the install command, rather than from the server
+    #[serde(default)]
+    pub standalone: bool,
+}
+
+impl StateData {
+    pub fn new(update_info: &UpdateInfo, state: ExternalState) -> StateData {
+        StateData {
+            version: SCHEMA_VERSION,
+            state: state,
+            deployment_id: update_info.id.clone(),
+            artifact_name: update_info.artifact.artifact_name.clone(),
+            payload_types: update_info
+                .artifact
+                .payload_types
+                .clone()
+                .unwrap_or_else(|| vec!["rootfs-image".to_string()]),
+            retries: 0,
+            state_count: 0,
+            loop_detected: false,
+            paused: false,
+            update_control: ControlState::default(),
+            standalone: false,
+        }
+    }
+
+    // A deployment of a local artifact, without a server.
+    pub fn standalone(artifact_name: &str, state: ExternalState) -> StateData {
+        StateData {
+            version: SCHEMA_VERSION,
+            state: state,
+            deployment_id: String::new(),
+            artifact_name: artifact_name.to_string(),
+            payload_types: vec!["rootfs-image".to_string()],
+            retries: 0,
+            state_count: 0,
+            loop_detected: false,
+            paused: false,
+            update_control: ControlState::default(),
+            standalone: true,
+        }
+    }
+
+    // Record entering the given state. Returns false if the deployment has
+    // entered more states than allowed, which means we are most likely stuck
+    // in a crash or reboot loop. The failure path then gets a fresh count of
+    // its own, so that it has a chance to restore the device.
+    pub fn enter(&mut self, state: ExternalState, max_count: u32) -> bool {
+        self.state = state;
+        self.paused = false;
+        self.state_count += 1;
+        if self.state_count <= max_count {
+            return true;
+        }
+        warn!(
+            "Deployment {} has entered {} states, the maximum is {}",
+            self.deployment_id, self.state_count, max_count
+        );
+        self.state_count = 0;
+        self.loop_detected = true;
+        false
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    Version(u32),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "IO error: {}", e),
+            StoreError::Parse(e) => write!(f, "Failed to parse the state data: {}", e),
+            StoreError::Version(v) => write!(
+                f,
+                "Unsupported state data version {}, expected {}",
+                v, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(error: std::io::Error) -> Self {
+        StoreError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(error: serde_json::Error) -> Self {
+        StoreError::Parse(error)
+    }
+}
+
+pub struct DataStore {
+    dir: PathBuf,
+}
+
+impl DataStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> DataStore {
+        DataStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(STATE_FILE)
+    }
+
+    // Returns the stored state, or None if there is no deployment in progress.
+    pub fn load(&self) -> Result<Option<StateData>, StoreError> {
+        let file = match File::open(self.path()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StoreError::Io(e)),
+        };
+        let data: StateData = serde_json::from_reader(BufReader::new(file))?;
+        if data.version != SCHEMA_VERSION {
+            return Err(StoreError::Version(data.version));
+        }
+        Ok(Some(data))
+    }
+
+    // Write to a temporary file, and rename it into place, so that a crash
+    // never leaves a half written file behind.
+    fn write(&self, name: &str, contents: &[u8]) -> Result<(), StoreError> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(contents)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, self.dir.join(name))?;
+        // Make sure the rename itself has hit the disk
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn store(&self, data: &StateData) -> Result<(), StoreError> {
+        debug!("Storing the state: {:?}", data.state);
+        self.write(STATE_FILE, &serde_json::to_vec(data)?)
+    }
+
+    // Returns when the periodic jobs last ran, in seconds since the epoch.
+    pub fn load_runs(&self) -> Result<HashMap<String, u64>, StoreError> {
+        match File::open(self.dir.join(RUNS_FILE)) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(StoreError::Io(e)),
+        }
+    }
+
+    pub fn store_runs(&self, runs: &HashMap<String, u64>) -> Result<(), StoreError> {
+        self.write(RUNS_FILE, &serde_json::to_vec(runs)?)
+    }
+
+    pub fn clear(&self) {
+        match fs::remove_file(self.path()) {
+            Ok(()) => debug!("Cleared the state data"),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove the state data: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_store(name: &str) -> DataStore {
+        let dir = std::env::temp_dir().join(format!("mender-datastore-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        DataStore::new(dir)
+    }
+
+    fn state_data() -> StateData {
+        StateData {
+            version: SCHEMA_VERSION,
+            state: ExternalState::ArtifactReboot,
+            deployment_id: "f4a7b80c-1dd7-415f-a020-834a8c9ce875".to_string(),
+            artifact_name: "release-1".to_string(),
+            payload_types: vec!["rootfs-image".to_string()],
+            retries: 0,
+            state_count: 0,
+            loop_detected: false,
+            paused: false,
+            update_control: ControlState::default(),
+            standalone: false,
+        }
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let store = test_store("roundtrip");
+        assert!(store.load().unwrap().is_none());
+        store.store(&state_data()).unwrap();
+        assert_eq!(store.load().unwrap(), Some(state_data()));
+        store.clear();
+        assert!(store.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_store_runs() {
+        let store = test_store("runs");
+        assert!(store.load_runs().unwrap().is_empty());
+        let mut runs = HashMap::new();
+        runs.insert("check-update".to_string(), 1583290800);
+        store.store_runs(&runs).unwrap();
+        assert_eq!(store.load_runs().unwrap(), runs);
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_enter_state_count() {
+        let mut data = state_data();
+        for _ in 0..3 {
+            assert!(data.enter(ExternalState::ArtifactCommit, 3));
+        }
+        assert!(!data.enter(ExternalState::ArtifactCommit, 3));
+        assert!(data.loop_detected);
+        assert_eq!(data.state_count, 0);
+        assert!(data.enter(ExternalState::ArtifactRollback, 3));
+        assert_eq!(data.state, ExternalState::ArtifactRollback);
+    }
+
+    #[test]
+    fn test_load_without_state_count() {
+        let store = test_store("compat");
+        fs::create_dir_all(&store.dir).unwrap();
+        fs::write(
+            store.path(),
+            r#"{"version":1,"state":"ArtifactReboot","deployment_id":"f4a7b80c-1dd7-415f-a020-834a8c9ce875","artifact_name":"release-1","payload_types":["rootfs-image"],"retries":0}"#,
+        )
+        .unwrap();
+        assert_eq!(store.load().unwrap(), Some(state_data()));
+        store.clear();
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let store = test_store("version");
+        let mut data = state_data();
+        data.version = SCHEMA_VERSION + 1;
+        store.store(&data).unwrap();
+        match store.load() {
+            Err(StoreError::Version(v)) => assert_eq!(v, SCHEMA_VERSION + 1),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        store.clear();
+    }
+}
@@ -0,0 +1,603 @@
+// delta module applies binary deltas in the VCDIFF format (RFC 3284), as made
+// by xdelta3, to build the new root filesystem from the one on the active
+// partition, so that only the differences are downloaded.
+//
+// A delta is a sequence of windows. Each window builds the next piece of the
+// target out of a segment of the source and the data in the window, by
+// instructions to ADD bytes of the data, RUN a byte a number of times, or
+// COPY bytes from the source segment or from earlier in the same window. The
+// windows are applied one at a time, and COPYs from the source segment are
+// read from the active partition as they come, so the memory needed is
+// bounded by the encoding and the target of a window, not by the source
+// segment or the partitions.
+//
+// Secondary compression and application defined code tables are not supported,
+// so deltas have to be made with `xdelta3 -S none`. The artifact compresses
+// them instead.
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::artifact::ArtifactError;
+
+const MAGIC: [u8; 4] = [0xd6, 0xc3, 0xc4, 0x00];
+
+// Bits of the header indicator
+const VCD_DECOMPRESS: u8 = 0x01;
+const VCD_CODETABLE: u8 = 0x02;
+const VCD_APPHEADER: u8 = 0x04;
+
+// Bits of the window indicator. VCD_ADLER32 is an extension of xdelta3, which
+// adds the checksum of the target window.
+const VCD_SOURCE: u8 = 0x01;
+const VCD_TARGET: u8 = 0x02;
+const VCD_ADLER32: u8 = 0x04;
+
+// The most memory taken by the encoding and the target of a window each.
+// xdelta3 makes target windows of 16 MiB at most.
+const MAX_WINDOW: u64 = 16 << 20;
+
+// The sizes of the address caches of the default code table.
+const NEAR: usize = 4;
+const SAME: usize = 3;
+
+#[derive(Debug)]
+pub enum DeltaError {
+    Io(io::Error),
+    Invalid(String),
+    Unsupported(&'static str),
+    // The target window at the given offset is not what it should be
+    Checksum(u64),
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::Io(e) => write!(f, "IO error: {}", e),
+            DeltaError::Invalid(msg) => write!(f, "Invalid delta: {}", msg),
+            DeltaError::Unsupported(what) => write!(f, "Unsupported delta: {}", what),
+            DeltaError::Checksum(offset) => write!(f, "Wrong checksum of the target at {}", offset),
+        }
+    }
+}
+
+impl From<io::Error> for DeltaError {
+    fn from(error: io::Error) -> Self {
+        DeltaError::Io(error)
+    }
+}
+
+impl From<DeltaError> for ArtifactError {
+    fn from(error: DeltaError) -> Self {
+        match error {
+            DeltaError::Io(e) => ArtifactError::Io(e),
+            e => ArtifactError::Install(e.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Noop,
+    Add,
+    Run,
+    // With the mode of the address
+    Copy(u8),
+}
+
+// An instruction of the code table. A size of 0 means the size follows the
+// instruction code.
+#[derive(Clone, Copy, Debug)]
+struct Instruction {
+    kind: Kind,
+    size: u8,
+}
+
+// The default code table of RFC 3284, section 5.6. Each code stands for one
+// instruction, or for two in a row.
+fn code_table() -> Vec<(Instruction, Instruction)> {
+    let op = |kind, size| Instruction { kind: kind, size: size };
+    let noop = op(Kind::Noop, 0);
+    let mut table = vec![(op(Kind::Run, 0), noop)];
+    for size in 0..18 {
+        table.push((op(Kind::Add, size), noop));
+    }
+    for mode in 0..9 {
+        table.push((op(Kind::Copy(mode), 0), noop));
+        for size in 4..19 {
+            table.push((op(Kind::Copy(mode), size), noop));
+        }
+    }
+    for mode in 0..6 {
+        for add in 1..5 {
+            for copy in 4..7 {
+                table.push((op(Kind::Add, add), op(Kind::Copy(mode), copy)));
+            }
+        }
+    }
+    for mode in 6..9 {
+        for add in 1..5 {
+            table.push((op(Kind::Add, add), op(Kind::Copy(mode), 4)));
+        }
+    }
+    for mode in 0..9 {
+        table.push((op(Kind::Copy(mode), 4), op(Kind::Add, 1)));
+    }
+    table
+}
+
+// Integers are written in base 128, most significant digit first, with the
+// top bit set on all digits but the last.
+fn integer(mut next: impl FnMut() -> Result<u8, DeltaError>) -> Result<u64, DeltaError> {
+    let mut value: u64 = 0;
+    loop {
+        let digit = next()?;
+        if value > u64::MAX >> 7 {
+            return Err(DeltaError::Invalid("Integer overflow".to_string()));
+        }
+        value = value << 7 | u64::from(digit & 0x7f);
+        if digit & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn read_byte<R: Read + ?Sized>(reader: &mut R) -> Result<u8, DeltaError> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_integer<R: Read + ?Sized>(reader: &mut R) -> Result<u64, DeltaError> {
+    integer(|| read_byte(reader))
+}
+
+fn limit(size: u64, what: &str) -> Result<usize, DeltaError> {
+    if size > MAX_WINDOW {
+        return Err(DeltaError::Invalid(format!("The {} is larger than {} bytes", what, MAX_WINDOW)));
+    }
+    Ok(size as usize)
+}
+
+// Section reads one of the parts of a window.
+struct Section<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Section<'a> {
+    fn new(data: &'a [u8]) -> Section<'a> {
+        Section { data: data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, size: u64) -> Result<&'a [u8], DeltaError> {
+        if size > self.data.len() as u64 {
+            return Err(DeltaError::Invalid("The window is truncated".to_string()));
+        }
+        let (taken, rest) = self.data.split_at(size as usize);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, DeltaError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn integer(&mut self) -> Result<u64, DeltaError> {
+        integer(|| self.byte())
+    }
+}
+
+// Segment is the part of the source a window copies from, which is read
+// from the source a COPY at a time.
+struct Segment<'a, S> {
+    source: &'a mut S,
+    position: u64,
+    size: u64,
+}
+
+impl<'a, S: Read + Seek> Segment<'a, S> {
+    // Append size bytes from the address in the segment to the target.
+    fn copy(&mut self, address: u64, size: u64, target: &mut Vec<u8>) -> Result<(), DeltaError> {
+        let from = self
+            .position
+            .checked_add(address)
+            .ok_or_else(|| DeltaError::Invalid("COPY from beyond the source".to_string()))?;
+        let start = target.len();
+        target.resize(start + size as usize, 0);
+        self.source.seek(SeekFrom::Start(from))?;
+        self.source.read_exact(&mut target[start..])?;
+        Ok(())
+    }
+}
+
+// The addresses of COPY instructions are encoded relative to the recent
+// ones, which are kept in the caches, see RFC 3284, section 5.1.
+struct AddressCache {
+    near: [u64; NEAR],
+    next: usize,
+    same: Vec<u64>,
+}
+
+impl AddressCache {
+    fn new() -> AddressCache {
+        AddressCache {
+            near: [0; NEAR],
+            next: 0,
+            same: vec![0; SAME * 256],
+        }
+    }
+
+    // Decode the address of a COPY at here, the current position in the
+    // source segment followed by the target window.
+    fn decode(&mut self, mode: u8, here: u64, addresses: &mut Section) -> Result<u64, DeltaError> {
+        let mode = mode as usize;
+        let address = match mode {
+            0 => Some(addresses.integer()?),
+            1 => here.checked_sub(addresses.integer()?),
+            _ if mode < 2 + NEAR => self.near[mode - 2].checked_add(addresses.integer()?),
+            _ => Some(self.same[(mode - 2 - NEAR) * 256 + addresses.byte()? as usize]),
+        };
+        let address = match address {
+            Some(address) if address < here => address,
+            _ => return Err(DeltaError::Invalid(format!("COPY from beyond {}", here))),
+        };
+        self.near[self.next] = address;
+        self.next = (self.next + 1) % NEAR;
+        self.same[(address % (SAME * 256) as u64) as usize] = address;
+        Ok(address)
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1, 0);
+    // The most bytes summed up before b can overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+// Apply the delta to the source, writing the result to the target. Returns
+// the size of the target.
+pub fn apply<S, D, T>(source: &mut S, delta: &mut D, target: &mut T) -> Result<u64, DeltaError>
+where
+    S: Read + Seek,
+    D: Read + ?Sized,
+    T: Write,
+{
+    let mut magic = [0; 4];
+    delta.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(DeltaError::Invalid("Not a VCDIFF delta".to_string()));
+    }
+    let indicator = read_byte(delta)?;
+    if indicator & VCD_DECOMPRESS != 0 {
+        return Err(DeltaError::Unsupported("secondary compression"));
+    }
+    if indicator & VCD_CODETABLE != 0 {
+        return Err(DeltaError::Unsupported("application defined code table"));
+    }
+    if indicator & VCD_APPHEADER != 0 {
+        let size = read_integer(delta)?;
+        io::copy(&mut delta.take(size), &mut io::sink())?;
+    }
+    let table = code_table();
+    let mut written = 0;
+    loop {
+        let mut indicator = [0];
+        if delta.read(&mut indicator)? == 0 {
+            return Ok(written);
+        }
+        let window = read_window(indicator[0], &table, source, delta, written)?;
+        target.write_all(&window)?;
+        written += window.len() as u64;
+    }
+}
+
+// Read the next window of the delta, and build its target, which goes at the
+// given offset of the whole target.
+fn read_window<S, D>(
+    indicator: u8,
+    table: &[(Instruction, Instruction)],
+    source: &mut S,
+    delta: &mut D,
+    offset: u64,
+) -> Result<Vec<u8>, DeltaError>
+where
+    S: Read + Seek,
+    D: Read + ?Sized,
+{
+    if indicator & VCD_TARGET != 0 {
+        return Err(DeltaError::Unsupported("COPY from earlier target windows"));
+    }
+    let mut segment = Segment {
+        source: source,
+        position: 0,
+        size: 0,
+    };
+    if indicator & VCD_SOURCE != 0 {
+        segment.size = read_integer(delta)?;
+        segment.position = read_integer(delta)?;
+        if segment.position.checked_add(segment.size).is_none() {
+            return Err(DeltaError::Invalid("The source segment is out of range".to_string()));
+        }
+    }
+    let mut encoding = vec![0; limit(read_integer(delta)?, "delta encoding")?];
+    delta.read_exact(&mut encoding)?;
+
+    let mut header = Section::new(&encoding);
+    let target_size = limit(header.integer()?, "target window")?;
+    if header.byte()? != 0 {
+        return Err(DeltaError::Unsupported("secondary compression"));
+    }
+    let data_size = header.integer()?;
+    let instructions_size = header.integer()?;
+    let addresses_size = header.integer()?;
+    let checksum = match indicator & VCD_ADLER32 {
+        0 => None,
+        _ => Some(u32::from_be_bytes(header.take(4)?.try_into().unwrap())),
+    };
+    let mut data = Section::new(header.take(data_size)?);
+    let mut instructions = Section::new(header.take(instructions_size)?);
+    let mut addresses = Section::new(header.take(addresses_size)?);
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut cache = AddressCache::new();
+    while !instructions.is_empty() {
+        let (first, second) = table[instructions.byte()? as usize];
+        for instruction in &[first, second] {
+            let size = match instruction {
+                Instruction { kind: Kind::Noop, .. } => continue,
+                Instruction { size: 0, .. } => instructions.integer()?,
+                Instruction { size, .. } => u64::from(*size),
+            };
+            if size > (target_size - target.len()) as u64 {
+                return Err(DeltaError::Invalid("The instructions overrun the target window".to_string()));
+            }
+            match instruction.kind {
+                Kind::Noop => {}
+                Kind::Add => target.extend_from_slice(data.take(size)?),
+                Kind::Run => {
+                    let byte = data.byte()?;
+                    target.resize(target.len() + size as usize, byte);
+                }
+                Kind::Copy(mode) => {
+                    let here = segment.size + target.len() as u64;
+                    let address = cache.decode(mode, here, &mut addresses)?;
+                    let from_source = segment.size.saturating_sub(address).min(size);
+                    if from_source > 0 {
+                        segment.copy(address, from_source, &mut target)?;
+                    }
+                    // The rest comes from earlier in the window, and may
+                    // overlap with what it produces, so it goes a byte at a
+                    // time
+                    if from_source < size {
+                        let start = (address + from_source - segment.size) as usize;
+                        for at in start..start + (size - from_source) as usize {
+                            target.push(target[at]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if target.len() != target_size || !data.is_empty() || !addresses.is_empty() || !header.is_empty() {
+        return Err(DeltaError::Invalid("The window does not add up".to_string()));
+    }
+    match checksum {
+        Some(checksum) if checksum != adler32(&target) => Err(DeltaError::Checksum(offset)),
+        _ => Ok(target),
+    }
+}
+
+// Build a delta of a single window, which copies what the target has in
+// common with the start of the source, and adds the rest, for the tests.
+#[cfg(test)]
+pub fn build(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let common = source.iter().zip(target).take_while(|(a, b)| a == b).count();
+    let mut instructions = Vec::new();
+    let mut addresses = Vec::new();
+    if common > 0 {
+        // COPY of a size that follows, from an address that follows
+        instructions.push(19);
+        instructions.extend(tests::integer(common as u64));
+        addresses.extend(tests::integer(0));
+    }
+    if common < target.len() {
+        // ADD of a size that follows
+        instructions.push(1);
+        instructions.extend(tests::integer((target.len() - common) as u64));
+    }
+    let mut delta = MAGIC.to_vec();
+    delta.push(0);
+    delta.extend(tests::window(
+        Some((common as u64, 0)),
+        target.len() as u64,
+        &target[common..],
+        &instructions,
+        &addresses,
+        Some(adler32(target)),
+    ));
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub fn integer(mut value: u64) -> Vec<u8> {
+        let mut digits = vec![(value & 0x7f) as u8];
+        value >>= 7;
+        while value > 0 {
+            digits.insert(0, (value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        digits
+    }
+
+    pub fn window(
+        source: Option<(u64, u64)>,
+        target_size: u64,
+        data: &[u8],
+        instructions: &[u8],
+        addresses: &[u8],
+        checksum: Option<u32>,
+    ) -> Vec<u8> {
+        let mut encoding = integer(target_size);
+        encoding.push(0);
+        encoding.extend(integer(data.len() as u64));
+        encoding.extend(integer(instructions.len() as u64));
+        encoding.extend(integer(addresses.len() as u64));
+        if let Some(checksum) = checksum {
+            encoding.extend(&checksum.to_be_bytes());
+        }
+        encoding.extend(data);
+        encoding.extend(instructions);
+        encoding.extend(addresses);
+
+        let mut indicator = 0;
+        let mut window = Vec::new();
+        if let Some((size, position)) = source {
+            indicator |= VCD_SOURCE;
+            window.extend(integer(size));
+            window.extend(integer(position));
+        }
+        if checksum.is_some() {
+            indicator |= VCD_ADLER32;
+        }
+        window.extend(integer(encoding.len() as u64));
+        window.extend(encoding);
+        window.insert(0, indicator);
+        window
+    }
+
+    fn apply_to(source: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+        let mut target = Vec::new();
+        let size = apply(&mut Cursor::new(source), &mut &delta[..], &mut target)?;
+        assert_eq!(size, target.len() as u64);
+        Ok(target)
+    }
+
+    #[test]
+    fn test_code_table() {
+        let table = code_table();
+        assert_eq!(table.len(), 256);
+        assert_eq!(table[19].0.kind, Kind::Copy(0));
+        assert_eq!(table[163].0.kind, Kind::Add);
+        assert_eq!(table[163].1.kind, Kind::Copy(0));
+        assert_eq!(table[255].0.kind, Kind::Copy(8));
+        assert_eq!(table[255].1.kind, Kind::Add);
+    }
+
+    #[test]
+    fn test_integer() {
+        assert_eq!(integer(123456789), vec![0xba, 0xef, 0x9a, 0x15]);
+        assert_eq!(Section::new(&[0xba, 0xef, 0x9a, 0x15]).integer().unwrap(), 123456789);
+        assert!(Section::new(&[0xff; 11]).integer().is_err());
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    }
+
+    #[test]
+    fn test_apply() {
+        let source = b"The quick brown fox jumps over the lazy dog";
+        // "The quick " from the source, an ADD of "red" combined with a COPY
+        // of " fox" from the source, the rest of the source from right after
+        // that (near cache), and a RUN of "!"
+        let first = window(
+            Some((source.len() as u64, 0)),
+            47,
+            b"red!",
+            &[19, 10, 169, 67, 24, 0, 6],
+            &[0, 15, 4],
+            Some(adler32(b"The quick red fox jumps over the lazy dog!!!!!!")),
+        );
+        // An ADD of " ", "The quick " from the source, again from earlier in
+        // the window (VCD_HERE), and "The " again (same cache)
+        let second = window(Some((10, 0)), 25, b" ", &[2, 26, 42, 116], &[0, 10, 0], None);
+        // A COPY overlapping with what it produces
+        let third = window(None, 8, b"ab", &[3, 38], &[2], None);
+        let mut delta = MAGIC.to_vec();
+        delta.push(0);
+        delta.extend(&first);
+        delta.extend(&second);
+        delta.extend(&third);
+        assert_eq!(
+            String::from_utf8(apply_to(source, &delta).unwrap()).unwrap(),
+            "The quick red fox jumps over the lazy dog!!!!!! The quick The quick The abababab"
+        );
+
+        // A COPY running from the source segment on into the window
+        let mut across = MAGIC.to_vec();
+        across.push(0);
+        across.extend(window(Some((4, 0)), 8, b"", &[19, 8], &[0], None));
+        assert_eq!(apply_to(b"abcd", &across).unwrap(), b"abcdabcd");
+
+        let target = b"The quick brown fox jumps over the lazy cat";
+        assert_eq!(apply_to(source, &build(source, target)).unwrap(), target.to_vec());
+        assert_eq!(apply_to(source, &build(source, b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let source = b"source";
+        let delta = build(source, b"sourcetarget");
+        assert!(apply_to(source, &delta).is_ok());
+
+        // The checksum of the window is off
+        let mut wrong = delta.clone();
+        let at = wrong.len() - 6;
+        wrong[at] ^= 1;
+        match apply_to(source, &wrong) {
+            Err(DeltaError::Checksum(0)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // The same, in the window after an ADD of 3 bytes
+        let mut second = MAGIC.to_vec();
+        second.push(0);
+        second.extend(window(None, 3, b"abc", &[4], &[], None));
+        second.extend(window(None, 2, b"de", &[3], &[], Some(0)));
+        match apply_to(source, &second) {
+            Err(DeltaError::Checksum(3)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // Not a delta
+        assert!(apply_to(source, b"sourcetarget").is_err());
+        // Truncated
+        assert!(apply_to(source, &delta[..delta.len() - 1]).is_err());
+        // Secondary compression
+        let mut compressed = delta.clone();
+        compressed[4] = VCD_DECOMPRESS;
+        match apply_to(source, &compressed) {
+            Err(DeltaError::Unsupported(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        // A COPY from beyond the current position
+        let mut beyond = MAGIC.to_vec();
+        beyond.push(0);
+        beyond.extend(window(None, 4, b"", &[20], &[0], None));
+        assert!(apply_to(source, &beyond).is_err());
+        // A source segment beyond the end of the source
+        let mut outside = MAGIC.to_vec();
+        outside.push(0);
+        outside.extend(window(Some((4, 4)), 4, b"", &[20], &[0], None));
+        assert!(apply_to(source, &outside).is_err());
+        // A source segment whose end does not fit in 64 bits
+        let mut overflow = MAGIC.to_vec();
+        overflow.push(0);
+        overflow.extend(window(Some((4, u64::MAX - 2)), 4, b"", &[20], &[0], None));
+        match apply_to(source, &overflow) {
+            Err(DeltaError::Invalid(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// eventqueue module holds the events fired by the scheduler until the state
+// machine gets to them. While the state machine is busy, i.e. downloading an
+// update, the jobs keep firing, so the queue keeps a single pending event per
+// job: a job firing again while its event is still pending is merged into
+// it. The events of a job are dropped once the job is stopped, as they are
+// stale by then, like authorization attempts once we are authorized.
+// Authorization goes ahead of the periodic sync.
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use super::Event;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueueMetrics {
+    // Events queued
+    pub queued: u64,
+    // Events merged into one already pending
+    pub merged: u64,
+    // Stale events dropped
+    pub dropped: u64,
+    // Events handed to the state machine
+    pub delivered: u64,
+}
+
+struct Pending {
+    job: String,
+    event: Event,
+}
+
+fn priority(event: &Event) -> u8 {
+    match event {
+        Event::AuthorizeAttempt => 1,
+        _ => 0,
+    }
+}
+
+#[derive(Default)]
+pub struct EventQueue {
+    pending: VecDeque<Pending>,
+    metrics: QueueMetrics,
+}
+
+impl EventQueue {
+    pub fn push(&mut self, job: &str, event: Event) {
+        if self.pending.iter().any(|p| p.job == job) {
+            debug!("eventqueue: Merged {:?} into the pending one", event);
+            self.metrics.merged += 1;
+            return;
+        }
+        self.metrics.queued += 1;
+        self.pending.push_back(Pending {
+            job: job.to_string(),
+            event: event,
+        });
+    }
+
+    // Take the most urgent event of the given jobs, the oldest first.
+    pub fn pop(&mut self, jobs: &[String]) -> Option<Event> {
+        let mut next: Option<(usize, u8)> = None;
+        for (i, p) in self.pending.iter().enumerate() {
+            let prio = priority(&p.event);
+            if jobs.contains(&p.job) && next.map_or(true, |(_, best)| prio > best) {
+                next = Some((i, prio));
+            }
+        }
+        let (i, _) = next?;
+        self.metrics.delivered += 1;
+        self.pending.remove(i).map(|p| p.event)
+    }
+
+    // Drop the pending event of the job.
+    pub fn drop_job(&mut self, job: &str) {
+        let len = self.pending.len();
+        self.pending.retain(|p| p.job != job);
+        let dropped = (len - self.pending.len()) as u64;
+        if dropped > 0 {
+            debug!("eventqueue: Dropped the stale events of {}", job);
+            self.metrics.dropped += dropped;
+        }
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut queue = EventQueue::default();
+        for _ in 0..5 {
+            queue.push("check-update", Event::CheckForUpdate);
+            queue.push("send-inventory", Event::SendInventory);
+        }
+        assert_eq!(queue.pending.len(), 2);
+        let all = jobs(&["check-update", "send-inventory"]);
+        match (queue.pop(&all), queue.pop(&all), queue.pop(&all)) {
+            (Some(Event::CheckForUpdate), Some(Event::SendInventory), None) => {}
+            r => panic!("Unexpected events: {:?}", r),
+        }
+        let metrics = queue.metrics();
+        assert_eq!((metrics.queued, metrics.merged, metrics.delivered), (2, 8, 2));
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut queue = EventQueue::default();
+        queue.push("send-inventory", Event::SendInventory);
+        queue.push("authorize", Event::AuthorizeAttempt);
+        match queue.pop(&jobs(&["send-inventory", "authorize"])) {
+            Some(Event::AuthorizeAttempt) => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        // Only the events of the given jobs
+        assert!(queue.pop(&jobs(&["authorize"])).is_none());
+    }
+
+    #[test]
+    fn test_drop_stale() {
+        let mut queue = EventQueue::default();
+        queue.push("authorize", Event::AuthorizeAttempt);
+        queue.push("check-update", Event::CheckForUpdate);
+        queue.drop_job("authorize");
+        queue.drop_job("authorize");
+        assert_eq!(queue.pending.len(), 1);
+        assert_eq!(queue.metrics().dropped, 1);
+    }
+}
@@ -0,0 +1,61 @@
+// logger module logs to a file, for when the client runs without anyone
+// watching its output. Logging to the terminal is left to simple_logger.
+use log::{Level, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+struct FileLogger {
+    level: Level,
+    file: Mutex<File>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut file = self.file.lock().unwrap();
+        let _ = writeln!(
+            file,
+            "{} {:<5} [{}] {}",
+            now,
+            record.level(),
+            record.module_path().unwrap_or_default(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().flush();
+    }
+}
+
+// Log at the given level, to the given file if any, or else to stderr.
+pub fn init(level: Level, path: Option<&str>) -> Result<(), String> {
+    let path = match path {
+        Some(path) => path,
+        None => return simple_logger::init_with_level(level).map_err(|e| e.to_string()),
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open the log file {}: {}", path, e))?;
+    log::set_boxed_logger(Box::new(FileLogger {
+        level: level,
+        file: Mutex::new(file),
+    }))
+    .map_err(|e| e.to_string())?;
+    log::set_max_level(level.to_level_filter());
+    Ok(())
+}
@@ -0,0 +1,594 @@
+// modules module installs the payloads the client does not know how to
+// install itself, by handing them to update modules following the update
+// module protocol version 3. The module for a payload type is the executable
+// /usr/share/mender/modules/v3/<payload_type>, and is called as
+//   <module> <State> <file tree>
+// where the file tree is a working directory with the header of the artifact
+// and what the module is given to install.
+//
+// During Download the payload files are streamed to the module: it reads the
+// name of the next file from the stream-next FIFO in the tree, and then the
+// file from the streams/<name> FIFO, until stream-next gives an empty name.
+// A module which exits without opening stream-next gets the files stored in
+// files/ instead.
+//
+// The single-file and directory modules are built into the client, see
+// builtin.
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time;
+
+use super::artifact::{ArtifactError, Header, Installer};
+use super::builtin::Builtin;
+use super::cli::ARTIFACT_INFO;
+use super::config::{self, ConfigError};
+
+pub const MODULES_DIR: &str = "/usr/share/mender/modules/v3";
+// In the data directory
+pub const MODULES_WORK_DIR: &str = "modules/v3";
+const DEVICE_TYPE: &str = "/var/lib/mender/device_type";
+
+// How often to look whether the module has opened a FIFO, or exited.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct ModuleConf {
+    #[serde(rename = "ModuleTimeoutSeconds")]
+    timeout: u64,
+}
+
+impl Default for ModuleConf {
+    fn default() -> Self {
+        ModuleConf { timeout: 14400 }
+    }
+}
+
+#[derive(Debug)]
+pub enum ModuleError {
+    Io(io::Error),
+    Failed(String, i32),
+    Killed(String),
+    Timeout(String),
+    // The module answered a query with something we do not understand
+    Answer(String, String),
+    // The module stopped reading the payload before it was all streamed
+    Stopped(String),
+    // The payload does not have what the module needs
+    Payload(String),
+    // The deployment was aborted while the module was running
+    Aborted(String),
+    // The payload type cannot name a module in the modules directory
+    PayloadType(String),
+}
+
+impl std::fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::Io(e) => write!(f, "IO error: {}", e),
+            ModuleError::Failed(state, code) => write!(f, "The update module failed {} with code {}", state, code),
+            ModuleError::Killed(state) => write!(f, "The update module was killed by a signal in {}", state),
+            ModuleError::Timeout(state) => write!(f, "The update module timed out in {}", state),
+            ModuleError::Answer(state, answer) => {
+                write!(f, "Unexpected answer of the update module to {}: {}", state, answer)
+            }
+            ModuleError::Stopped(name) => write!(f, "The update module exited without reading {}", name),
+            ModuleError::Payload(msg) => write!(f, "Invalid payload: {}", msg),
+            ModuleError::Aborted(state) => write!(f, "The deployment was aborted in {}", state),
+            ModuleError::PayloadType(name) => write!(f, "Invalid payload type {:?}", name),
+        }
+    }
+}
+
+impl From<io::Error> for ModuleError {
+    fn from(error: io::Error) -> Self {
+        ModuleError::Io(error)
+    }
+}
+
+impl From<ModuleError> for ArtifactError {
+    fn from(error: ModuleError) -> Self {
+        ArtifactError::Install(error.to_string())
+    }
+}
+
+// Whether the update needs a reboot to be applied, as answered to
+// NeedsArtifactReboot. Automatic leaves the reboot to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reboot {
+    No,
+    Yes,
+    Automatic,
+}
+
+#[derive(Clone)]
+pub struct UpdateModules {
+    dir: PathBuf,
+    work_dir: PathBuf,
+    timeout: time::Duration,
+}
+
+impl UpdateModules {
+    // The modules of the daemon, working in the given data directory.
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Result<UpdateModules, ConfigError> {
+        Ok(UpdateModules::from_conf(
+            PathBuf::from(MODULES_DIR),
+            data_dir.as_ref().join(MODULES_WORK_DIR),
+            config::load()?,
+        ))
+    }
+
+    // Modules found in the given directory, working in the given directory,
+    // with the default timeout.
+    pub fn with_dirs<P: AsRef<Path>>(dir: P, work_dir: P) -> UpdateModules {
+        UpdateModules::from_conf(dir, work_dir, ModuleConf::default())
+    }
+
+    fn from_conf<P: AsRef<Path>>(dir: P, work_dir: P, conf: ModuleConf) -> UpdateModules {
+        UpdateModules {
+            dir: dir.as_ref().to_path_buf(),
+            work_dir: work_dir.as_ref().to_path_buf(),
+            timeout: time::Duration::from_secs(conf.timeout),
+        }
+    }
+
+    // The module installing the given payload type. Only artifacts with a
+    // single payload are supported, so it always works in payload 0000. The
+    // payload type comes from the server or the artifact, so it has to be a
+    // plain file name, which cannot point outside of the modules directory.
+    pub fn module(&self, payload_type: &str) -> Result<UpdateModule, ModuleError> {
+        if payload_type.is_empty()
+            || payload_type.contains('/')
+            || payload_type.starts_with('.')
+            || Path::new(payload_type).is_absolute()
+        {
+            return Err(ModuleError::PayloadType(payload_type.to_string()));
+        }
+        Ok(UpdateModule {
+            path: self.dir.join(payload_type),
+            builtin: Builtin::find(payload_type),
+            payloads: self.work_dir.join("payloads"),
+            tree: self.work_dir.join("payloads").join("0000").join("tree"),
+            timeout: self.timeout,
+        })
+    }
+}
+
+pub struct UpdateModule {
+    path: PathBuf,
+    // Installed by the client itself, instead of the module at the path
+    builtin: Option<Builtin>,
+    payloads: PathBuf,
+    tree: PathBuf,
+    timeout: time::Duration,
+}
+
+impl UpdateModule {
+    fn command(&self, state: &str) -> Command {
+        let mut command = Command::new(&self.path);
+        command.arg(state).arg(&self.tree).current_dir(&self.tree);
+        command
+    }
+
+    // Run the module for the given state, and return what it printed.
+    pub fn call(&self, state: &str) -> Result<String, ModuleError> {
+        self.run(state, &mut || true)
+    }
+
+    // Run the module like call, calling beat at every interval while it
+    // runs. Once beat tells that the deployment is aborted, the module is
+    // killed.
+    pub fn call_with_heartbeat<F: FnMut() -> bool>(
+        &self,
+        state: &str,
+        interval: time::Duration,
+        mut beat: F,
+    ) -> Result<String, ModuleError> {
+        let mut last = time::Instant::now();
+        self.run(state, &mut || {
+            if last.elapsed() < interval {
+                return true;
+            }
+            last = time::Instant::now();
+            beat()
+        })
+    }
+
+    fn run(&self, state: &str, beat: &mut dyn FnMut() -> bool) -> Result<String, ModuleError> {
+        if let Some(builtin) = self.builtin {
+            return builtin.call(state, &self.tree);
+        }
+        debug!("Calling the update module {:?} for {}", self.path, state);
+        let mut child = self.command(state).stdout(Stdio::piped()).spawn()?;
+        let mut stdout = child.stdout.take().unwrap();
+        let output = thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        });
+        let status = wait(&mut child, state, time::Instant::now() + self.timeout, beat)?;
+        let output = output.join().unwrap()?;
+        check(state, status)?;
+        Ok(output)
+    }
+
+    pub fn needs_reboot(&self) -> Result<Reboot, ModuleError> {
+        let answer = self.call("NeedsArtifactReboot")?;
+        match answer.trim() {
+            "" | "No" => Ok(Reboot::No),
+            "Yes" => Ok(Reboot::Yes),
+            "Automatic" => Ok(Reboot::Automatic),
+            other => Err(ModuleError::Answer("NeedsArtifactReboot".to_string(), other.to_string())),
+        }
+    }
+
+    pub fn supports_rollback(&self) -> Result<bool, ModuleError> {
+        let answer = self.call("SupportsRollback")?;
+        match answer.trim() {
+            "" | "No" => Ok(false),
+            "Yes" => Ok(true),
+            other => Err(ModuleError::Answer("SupportsRollback".to_string(), other.to_string())),
+        }
+    }
+
+    // Let the module clean up after the deployment, and remove the payloads
+    // with their trees.
+    pub fn cleanup(&self) {
+        if !self.payloads.exists() {
+            return;
+        }
+        if self.tree.exists() {
+            if let Err(e) = self.call("Cleanup") {
+                warn!("{}", e);
+            }
+        }
+        if let Err(e) = fs::remove_dir_all(&self.payloads) {
+            warn!("Failed to remove {:?}: {}", self.payloads, e);
+        }
+    }
+
+    // The installer running the Download state of the module.
+    pub fn download(&self) -> Download<'_> {
+        Download {
+            module: self,
+            child: None,
+            streaming: false,
+            deadline: time::Instant::now() + self.timeout,
+        }
+    }
+
+    // Build a fresh tree for the artifact.
+    fn prepare(&self, header: &Header) -> io::Result<()> {
+        if self.tree.exists() {
+            fs::remove_dir_all(&self.tree)?;
+        }
+        for dir in &["header", "tmp", "streams", "files"] {
+            fs::create_dir_all(self.tree.join(dir))?;
+        }
+        let payload = &header.payloads[0];
+        let artifact_name = read_value(ARTIFACT_INFO, "artifact_name");
+        let artifact_group = read_value(ARTIFACT_INFO, "artifact_group");
+        let device_type = read_value(DEVICE_TYPE, "device_type");
+        let files: &[(&str, &[u8])] = &[
+            ("version", b"3\n"),
+            ("current_artifact_name", artifact_name.as_bytes()),
+            ("current_artifact_group", artifact_group.as_bytes()),
+            ("current_device_type", device_type.as_bytes()),
+            ("header/artifact_name", header.artifact_name.as_bytes()),
+            ("header/artifact_group", header.artifact_group.as_bytes()),
+            ("header/payload_type", payload.payload_type.as_bytes()),
+            ("header/header-info", &header.header_info),
+            ("header/type-info", &payload.type_info),
+            ("header/meta-data", &payload.meta_data),
+        ];
+        for (name, content) in files {
+            fs::write(self.tree.join(name), content)?;
+        }
+        mkfifo(&self.tree.join("stream-next"))
+    }
+}
+
+// Download runs the module in the Download state, while the artifact is read.
+pub struct Download<'a> {
+    module: &'a UpdateModule,
+    child: Option<Child>,
+    // The module has opened stream-next, so it takes the files as streams
+    streaming: bool,
+    deadline: time::Instant,
+}
+
+impl<'a> Download<'a> {
+    // Open the FIFO for writing, once the module opens it for reading.
+    // Returns None if the module exits instead.
+    fn open_fifo(&mut self, path: &Path) -> Result<Option<File>, ModuleError> {
+        let child = match self.child {
+            Some(ref mut child) => child,
+            None => return Ok(None),
+        };
+        loop {
+            match OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path) {
+                Ok(fifo) => {
+                    set_blocking(&fifo)?;
+                    return Ok(Some(fifo));
+                }
+                // Nobody is reading it yet
+                Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => {}
+                Err(e) => return Err(ModuleError::Io(e)),
+            }
+            if let Some(status) = child.try_wait()? {
+                self.child = None;
+                check("Download", status)?;
+                return Ok(None);
+            }
+            if time::Instant::now() > self.deadline {
+                return Err(ModuleError::Timeout("Download".to_string()));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn stream(&mut self, name: &str, content: &mut dyn Read) -> Result<bool, ModuleError> {
+        let mut next = match self.open_fifo(&self.module.tree.join("stream-next"))? {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        self.streaming = true;
+        let stream = Path::new("streams").join(name);
+        mkfifo(&self.module.tree.join(&stream))?;
+        writeln!(next, "{}", stream.display())?;
+        drop(next);
+        let mut fifo = self
+            .open_fifo(&self.module.tree.join(&stream))?
+            .ok_or_else(|| ModuleError::Stopped(name.to_string()))?;
+        io::copy(content, &mut fifo)?;
+        Ok(true)
+    }
+}
+
+impl<'a> Installer for Download<'a> {
+    fn header(&mut self, header: &Header) -> Result<(), ArtifactError> {
+        if header.payloads.len() != 1 {
+            return Err(ArtifactError::Install("Only artifacts with a single payload are supported".to_string()));
+        }
+        self.module.prepare(header)?;
+        // The built-in modules take the files stored in the tree
+        if self.module.builtin.is_none() {
+            info!("Downloading the {} payload with {:?}", header.payloads[0].payload_type, self.module.path);
+            self.child = Some(self.module.command("Download").spawn()?);
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, _payload: usize, name: &str, _size: u64, content: &mut dyn Read) -> Result<(), ArtifactError> {
+        if name.contains('/') {
+            return Err(ArtifactError::Invalid(format!("Payload file {} in a sub directory", name)));
+        }
+        if self.stream(name, content)? {
+            return Ok(());
+        }
+        if self.streaming {
+            return Err(ModuleError::Stopped(name.to_string()).into());
+        }
+        // The module did not want the streams, so store the files for it
+        let mut file = File::create(self.module.tree.join("files").join(name))?;
+        io::copy(content, &mut file)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), ArtifactError> {
+        // An empty name tells the module there are no more files
+        drop(self.open_fifo(&self.module.tree.join("stream-next"))?);
+        if let Some(mut child) = self.child.take() {
+            check("Download", wait(&mut child, "Download", self.deadline, &mut || true)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Download<'a> {
+    // Do not leave the module behind if the download failed.
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Wait for the module to exit, killing it at the deadline, or once beat
+// tells that the deployment is aborted.
+fn wait(
+    child: &mut Child,
+    state: &str,
+    deadline: time::Instant,
+    beat: &mut dyn FnMut() -> bool,
+) -> Result<ExitStatus, ModuleError> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if !beat() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ModuleError::Aborted(state.to_string()));
+        }
+        if time::Instant::now() > deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ModuleError::Timeout(state.to_string()));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn check(state: &str, status: ExitStatus) -> Result<(), ModuleError> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(ModuleError::Failed(state.to_string(), code)),
+        None => Err(ModuleError::Killed(state.to_string())),
+    }
+}
+
+fn mkfifo(path: &Path) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_blocking(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Read the value of key=value from the given file, or nothing if it is not
+// there.
+fn read_value(path: &str, key: &str) -> String {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(value)) if k.trim() == key => Some(value.trim().to_string()),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn setup(name: &str, module: &str) -> (PathBuf, UpdateModules) {
+        let dir = std::env::temp_dir().join(format!("mender-modules-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("modules")).unwrap();
+        let path = dir.join("modules").join("app");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", module)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        (dir.clone(), UpdateModules::with_dirs(dir.join("modules"), dir.join("work")))
+    }
+
+    // Copies whatever it is given to installed/, and logs the states.
+    const STREAMING_MODULE: &str = r#"
+        echo "$1" >> ../../../../log
+        case "$1" in
+        Download)
+            mkdir -p ../../../../installed
+            while read -r stream < stream-next && [ -n "$stream" ]; do
+                cat "$stream" > "../../../../installed/$(basename "$stream")"
+            done
+            ;;
+        NeedsArtifactReboot) echo Automatic ;;
+        SupportsRollback) echo Yes ;;
+        esac
+    "#;
+
+    #[test]
+    fn test_streaming_download() {
+        let (dir, modules) = setup("streaming", STREAMING_MODULE);
+        let module = modules.module("app").unwrap();
+        let artifact = artifact::build("app", &[("app.conf", b"port=80\n"), ("app", b"binary")], &[]);
+        artifact::read(&artifact[..], &mut module.download()).unwrap();
+        assert_eq!(fs::read(dir.join("installed/app.conf")).unwrap(), b"port=80\n");
+        assert_eq!(fs::read(dir.join("installed/app")).unwrap(), b"binary");
+        let tree = dir.join("work/payloads/0000/tree");
+        assert_eq!(fs::read_to_string(tree.join("header/payload_type")).unwrap(), "app");
+        assert_eq!(fs::read_to_string(tree.join("header/artifact_name")).unwrap(), "release-2");
+        assert_eq!(module.needs_reboot().unwrap(), Reboot::Automatic);
+        assert!(module.supports_rollback().unwrap());
+        module.call("ArtifactInstall").unwrap();
+        module.cleanup();
+        assert!(!dir.join("work/payloads").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("log")).unwrap(),
+            "Download\nNeedsArtifactReboot\nSupportsRollback\nArtifactInstall\nCleanup\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_payload_type() {
+        let modules = UpdateModules::with_dirs("/usr/share/mender/modules/v3", "/var/lib/mender/modules/v3");
+        assert!(modules.module("app").is_ok());
+        for payload_type in &["", "../../../bin/sh", "/bin/sh", "app/sh", ".hidden", ".."] {
+            match modules.module(payload_type) {
+                Err(ModuleError::PayloadType(_)) => {}
+                _ => panic!("Accepted the payload type {:?}", payload_type),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stored_files() {
+        // Never opens stream-next, so the files are stored in the tree
+        let (dir, modules) = setup("stored", "exit 0");
+        let module = modules.module("app").unwrap();
+        let artifact = artifact::build("app", &[("app", b"binary")], &[]);
+        artifact::read(&artifact[..], &mut module.download()).unwrap();
+        let tree = dir.join("work/payloads/0000/tree");
+        assert_eq!(fs::read(tree.join("files/app")).unwrap(), b"binary");
+        assert_eq!(module.needs_reboot().unwrap(), Reboot::No);
+        assert!(!module.supports_rollback().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aborted_module() {
+        let (dir, modules) = setup("aborted", "sleep 10");
+        let module = modules.module("app").unwrap();
+        fs::create_dir_all(dir.join("work/payloads/0000/tree")).unwrap();
+        let mut beats = 0;
+        let start = time::Instant::now();
+        let result = module.call_with_heartbeat("ArtifactInstall", time::Duration::from_secs(0), || {
+            beats += 1;
+            beats < 3
+        });
+        match result {
+            Err(ModuleError::Aborted(ref state)) if state == "ArtifactInstall" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(beats, 3);
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failing_module() {
+        let (dir, modules) = setup("failing", "exit 3");
+        let module = modules.module("app").unwrap();
+        let artifact = artifact::build("app", &[("app", b"binary")], &[]);
+        match artifact::read(&artifact[..], &mut module.download()) {
+            Err(ArtifactError::Install(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match module.call("ArtifactInstall") {
+            Err(ModuleError::Failed(ref state, 3)) if state == "ArtifactInstall" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        fs::write(dir.join("modules/app"), "#!/bin/sh\necho Maybe\n").unwrap();
+        match module.needs_reboot() {
+            Err(ModuleError::Answer(_, ref answer)) if answer == "Maybe" => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
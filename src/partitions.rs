@@ -0,0 +1,262 @@
+// partitions module figures out which of the two root filesystem partitions
+// the device is running from (the active one), and which one an update can
+// safely be written to (the passive one).
+use log::{debug, info};
+use serde::Deserialize;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::config::{self, ConfigError};
+
+// The two rootfs partitions as configured in /etc/mender/mender.conf.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct PartitionConf {
+    #[serde(rename = "RootfsPartA")]
+    rootfs_part_a: String,
+    #[serde(rename = "RootfsPartB")]
+    rootfs_part_b: String,
+}
+
+#[derive(Debug)]
+pub enum PartitionError {
+    Io(std::io::Error),
+    Config(ConfigError),
+    NotConfigured,
+    NoRootDevice,
+    Unresolved(String),
+    UnknownActive(String),
+    NoPartitionNumber(String),
+    MountedRoot(String),
+}
+
+impl std::fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionError::Io(e) => write!(f, "IO error: {}", e),
+            PartitionError::Config(e) => write!(f, "{}", e),
+            PartitionError::NotConfigured => write!(f, "RootfsPartA and RootfsPartB are not configured"),
+            PartitionError::NoRootDevice => write!(f, "Unable to find the root device"),
+            PartitionError::Unresolved(dev) => write!(f, "Unable to resolve the device: {}", dev),
+            PartitionError::UnknownActive(dev) => {
+                write!(f, "The root device {} is neither RootfsPartA nor RootfsPartB", dev)
+            }
+            PartitionError::NoPartitionNumber(dev) => {
+                write!(f, "Unable to find the partition number of {}", dev)
+            }
+            PartitionError::MountedRoot(dev) => {
+                write!(f, "Refusing to write to the mounted root filesystem: {}", dev)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for PartitionError {
+    fn from(error: std::io::Error) -> Self {
+        PartitionError::Io(error)
+    }
+}
+
+impl From<ConfigError> for PartitionError {
+    fn from(error: ConfigError) -> Self {
+        PartitionError::Config(error)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Partitions {
+    pub active: String,
+    pub passive: String,
+}
+
+impl Partitions {
+    // Detect the active partition from the kernel command line, and fall back
+    // to the device backing '/' in the mount table if that is inconclusive.
+    pub fn detect() -> Result<Partitions, PartitionError> {
+        let conf: PartitionConf = config::load()?;
+        if conf.rootfs_part_a.is_empty() || conf.rootfs_part_b.is_empty() {
+            return Err(PartitionError::NotConfigured);
+        }
+        let part_a = resolve_device(&conf.rootfs_part_a)?;
+        let part_b = resolve_device(&conf.rootfs_part_b)?;
+
+        let cmdline = fs::read_to_string("/proc/cmdline")?;
+        if let Some(root) = root_from_cmdline(&cmdline) {
+            debug!("Kernel command line root: {}", root);
+            if let Ok(root) = resolve_device(&root) {
+                if let Some(partitions) = Partitions::pick(&root, &part_a, &part_b) {
+                    return Ok(partitions);
+                }
+            }
+        }
+
+        // root= was missing, or something like /dev/root which does not
+        // tell us anything. Compare device numbers with the mounted root.
+        let (major, minor) = mounted_root()?;
+        debug!("Root filesystem is mounted from device {}:{}", major, minor);
+        for dev in &[&part_a, &part_b] {
+            if device_number(dev)? == (major, minor) {
+                if let Some(partitions) = Partitions::pick(dev, &part_a, &part_b) {
+                    return Ok(partitions);
+                }
+            }
+        }
+        Err(PartitionError::UnknownActive(format!("{}:{}", major, minor)))
+    }
+
+    fn pick(root: &str, part_a: &str, part_b: &str) -> Option<Partitions> {
+        if root == part_a {
+            Some(Partitions::new(part_a, part_b))
+        } else if root == part_b {
+            Some(Partitions::new(part_b, part_a))
+        } else {
+            None
+        }
+    }
+
+    fn new(active: &str, passive: &str) -> Partitions {
+        info!("Active partition: {}, passive partition: {}", active, passive);
+        Partitions {
+            active: active.to_string(),
+            passive: passive.to_string(),
+        }
+    }
+
+    // The device an update can be written to. Never the mounted root.
+    pub fn write_target(&self) -> Result<&str, PartitionError> {
+        if device_number(&self.passive)? == mounted_root()? {
+            return Err(PartitionError::MountedRoot(self.passive.clone()));
+        }
+        Ok(&self.passive)
+    }
+
+    // The value of mender_boot_part which boots the passive partition.
+    pub fn passive_number(&self) -> Result<u32, PartitionError> {
+        partition_number(&self.passive)
+            .ok_or_else(|| PartitionError::NoPartitionNumber(self.passive.clone()))
+    }
+
+    // The value of mender_boot_part which boots the active partition.
+    pub fn active_number(&self) -> Result<u32, PartitionError> {
+        partition_number(&self.active)
+            .ok_or_else(|| PartitionError::NoPartitionNumber(self.active.clone()))
+    }
+}
+
+// Find the value of root= on the kernel command line.
+fn root_from_cmdline(cmdline: &str) -> Option<String> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("root="))
+        .next_back()
+        .map(|root| root.to_string())
+}
+
+// Turn the UUID=, PARTUUID= and LABEL= forms into a device path,
+// and follow any /dev/disk/by-* symlinks to the real device node.
+fn resolve_device(dev: &str) -> Result<String, PartitionError> {
+    let path = if let Some(uuid) = dev.strip_prefix("UUID=") {
+        format!("/dev/disk/by-uuid/{}", uuid)
+    } else if let Some(partuuid) = dev.strip_prefix("PARTUUID=") {
+        format!("/dev/disk/by-partuuid/{}", partuuid.to_lowercase())
+    } else if let Some(label) = dev.strip_prefix("LABEL=") {
+        format!("/dev/disk/by-label/{}", label)
+    } else {
+        dev.to_string()
+    };
+    match fs::canonicalize(Path::new(&path)) {
+        Ok(p) => Ok(p.to_string_lossy().into_owned()),
+        Err(_) => Err(PartitionError::Unresolved(dev.to_string())),
+    }
+}
+
+// The major and minor numbers of the device mounted on '/'.
+fn mounted_root() -> Result<(u64, u64), PartitionError> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    root_from_mountinfo(&mountinfo).ok_or(PartitionError::NoRootDevice)
+}
+
+// Each line in mountinfo looks like:
+// 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+// where the third field is major:minor, and the fifth is the mount point.
+fn root_from_mountinfo(mountinfo: &str) -> Option<(u64, u64)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[4] != "/" {
+                return None;
+            }
+            let mut numbers = fields[2].split(':');
+            let major = numbers.next()?.parse().ok()?;
+            let minor = numbers.next()?.parse().ok()?;
+            Some((major, minor))
+        })
+        .next_back()
+}
+
+fn device_number(dev: &str) -> Result<(u64, u64), PartitionError> {
+    let rdev = fs::metadata(dev)?.rdev();
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    Ok((major, minor))
+}
+
+// The trailing digits of the device name, i.e. 3 for /dev/mmcblk0p3.
+pub fn partition_number(dev: &str) -> Option<u32> {
+    let digits: String = dev
+        .chars()
+        .rev()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<Vec<char>>()
+        .into_iter()
+        .rev()
+        .collect();
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root_from_cmdline() {
+        let cmdline = "console=ttyS0,115200 root=/dev/mmcblk0p2 rootwait ro\n";
+        assert_eq!(root_from_cmdline(cmdline), Some("/dev/mmcblk0p2".to_string()));
+        let cmdline = "root=PARTUUID=26445670-f37c-408b-be2c-3ef419866620 quiet";
+        assert_eq!(
+            root_from_cmdline(cmdline),
+            Some("PARTUUID=26445670-f37c-408b-be2c-3ef419866620".to_string())
+        );
+        assert_eq!(root_from_cmdline("console=ttyS0 quiet"), None);
+    }
+
+    #[test]
+    fn test_root_from_mountinfo() {
+        let mountinfo = "22 1 8:3 / / rw,relatime shared:1 - ext4 /dev/sda3 rw\n\
+                         23 22 0:21 / /proc rw,nosuid shared:12 - proc proc rw\n\
+                         24 22 8:1 / /boot rw,relatime shared:2 - vfat /dev/sda1 rw\n";
+        assert_eq!(root_from_mountinfo(mountinfo), Some((8, 3)));
+        assert_eq!(root_from_mountinfo(""), None);
+    }
+
+    #[test]
+    fn test_partition_number() {
+        assert_eq!(partition_number("/dev/mmcblk0p3"), Some(3));
+        assert_eq!(partition_number("/dev/hda2"), Some(2));
+        assert_eq!(partition_number("/dev/sda12"), Some(12));
+        assert_eq!(partition_number("/dev/sda"), None);
+    }
+
+    #[test]
+    fn test_pick() {
+        let p = Partitions::pick("/dev/sda2", "/dev/sda2", "/dev/sda3").unwrap();
+        assert_eq!(p.active, "/dev/sda2");
+        assert_eq!(p.passive, "/dev/sda3");
+        let p = Partitions::pick("/dev/sda3", "/dev/sda2", "/dev/sda3").unwrap();
+        assert_eq!(p.active, "/dev/sda3");
+        assert_eq!(p.passive, "/dev/sda2");
+        assert!(Partitions::pick("/dev/sda1", "/dev/sda2", "/dev/sda3").is_none());
+    }
+}
@@ -0,0 +1,171 @@
+// runtime module runs the network I/O and the timers of the daemon on a single
+// async runtime, on a thread of its own. The rest of the daemon, like the
+// state machine, stays blocking, and waits for what it hands to the runtime.
+//
+// The runtime uses the basic scheduler, which runs everything on that one
+// thread, and few blocking threads for resolving host names, so that it stays
+// small on small boards. The daemon only waits on a few sockets and timers.
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use lazy_static::lazy_static;
+use std::future::Future;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::{self, Handle};
+use tokio::sync::watch;
+
+// Resolving host names blocks, so it runs on a few threads of its own.
+const MAX_BLOCKING_THREADS: usize = 2;
+
+lazy_static! {
+    static ref RUNTIME: Handle = start();
+}
+
+fn start() -> Handle {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("runtime".to_string())
+        .spawn(move || {
+            let mut rt = runtime::Builder::new()
+                .basic_scheduler()
+                .enable_all()
+                .max_threads(MAX_BLOCKING_THREADS)
+                .build()
+                .expect("Failed to start the runtime");
+            tx.send(rt.handle().clone()).unwrap();
+            rt.block_on(future::pending::<()>());
+        })
+        .expect("Failed to start the runtime thread");
+    rx.recv().expect("Failed to start the runtime")
+}
+
+// Run the future on the runtime, in the background.
+pub fn spawn<F>(f: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    RUNTIME.spawn(f);
+}
+
+// Run the future on the runtime, and wait for it. Must not be called from
+// the runtime itself.
+pub fn block_on<F>(f: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send,
+{
+    let (tx, rx) = oneshot::channel();
+    RUNTIME.spawn(async move {
+        let _ = tx.send(f.await);
+    });
+    futures::executor::block_on(rx).expect("The runtime stopped")
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Interrupted {
+    TimedOut,
+    Cancelled,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interrupted::TimedOut => write!(f, "Timed out"),
+            Interrupted::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+// Cancel is shared by what cancels, i.e. a shutdown, and what is cancelled.
+#[derive(Clone)]
+pub struct Cancel {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Cancel {
+    fn default() -> Cancel {
+        let (tx, rx) = watch::channel(false);
+        Cancel { tx: Arc::new(tx), rx: rx }
+    }
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        let _ = self.tx.broadcast(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    // Completes once cancelled.
+    pub async fn cancelled(&self) {
+        if self.is_cancelled() {
+            return;
+        }
+        let mut rx = self.rx.clone();
+        while let Some(cancelled) = rx.recv().await {
+            if cancelled {
+                return;
+            }
+        }
+    }
+}
+
+// Run the future, giving up on it after the timeout, or once cancelled. If
+// cancelled already, it is not run at all.
+pub async fn limit<F: Future>(f: F, timeout: Duration, cancel: Option<Cancel>) -> Result<F::Output, Interrupted> {
+    let cancelled = Box::pin(async move {
+        match cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => future::pending().await,
+        }
+    });
+    let timed = Box::pin(tokio::time::timeout(timeout, f));
+    match future::select(cancelled, timed).await {
+        Either::Left(_) => Err(Interrupted::Cancelled),
+        Either::Right((Ok(output), _)) => Ok(output),
+        Either::Right((Err(_), _)) => Err(Interrupted::TimedOut),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_on() {
+        assert_eq!(block_on(async { 42 }), 42);
+        // Timers are made on the runtime
+        let delay = async { tokio::time::delay_for(Duration::from_millis(10)).await };
+        let delayed = block_on(limit(delay, Duration::from_secs(10), None));
+        assert_eq!(delayed, Ok(()));
+    }
+
+    #[test]
+    fn test_limit() {
+        let hung = block_on(limit(future::pending::<()>(), Duration::from_millis(10), None));
+        assert_eq!(hung, Err(Interrupted::TimedOut));
+
+        let cancel = Cancel::new();
+        let waiting = {
+            let cancel = cancel.clone();
+            thread::spawn(move || block_on(limit(future::pending::<()>(), Duration::from_secs(60), Some(cancel))))
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert!(!cancel.is_cancelled());
+        cancel.cancel();
+        assert_eq!(waiting.join().unwrap(), Err(Interrupted::Cancelled));
+        // Cancelled already, so it is not even started
+        let cancelled = block_on(limit(async { 42 }, Duration::from_secs(60), Some(cancel)));
+        assert_eq!(cancelled, Err(Interrupted::Cancelled));
+    }
+}
@@ -0,0 +1,632 @@
+// scheduler module runs the jobs which produce the events of the state
+// machine: authorization attempts until the client is authorized, and after
+// that update checks and inventory updates. Jobs are either periodic or
+// one-shot, and are added stopped. A single timer task on the async runtime
+// fires them, and ends whenever no job is running, so stopping every job ends
+// the task.
+//
+// The state machine reads the events of a group of jobs through JobEvents,
+// which starts and stops the jobs of the group as a whole. The events wait in
+// an EventQueue until it does. One-shot jobs are left out when a group starts;
+// rescheduling one starts it.
+//
+// Periodic jobs may wait a random extra time, up to their jitter, on top of
+// their interval, so that devices booted at the same time do not all poll the
+// server at once. The jobs given to with_store persist when they last ran, so
+// that after a restart they run when the interval is up, rather than at once.
+use log::{debug, warn};
+use serde::Deserialize;
+use futures::future;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+use super::clock::{Clock, SystemClock};
+use super::datastore::DataStore;
+use super::eventqueue::{EventQueue, QueueMetrics};
+use super::runtime;
+use super::{Event, EventProducer};
+
+pub const AUTHORIZE: &str = "authorize";
+pub const CHECK_UPDATE: &str = "check-update";
+pub const SEND_INVENTORY: &str = "send-inventory";
+// An update check asked for on the daemon socket, some time from now
+pub const CHECK_UPDATE_ONCE: &str = "check-update-once";
+
+// How often to try to authorize until we are.
+const AUTHORIZE_INTERVAL: Duration = Duration::from_secs(30);
+
+// These are based on the config file parameters found in:
+// /etc/mender/mender.conf
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct IntervalConf {
+    #[serde(rename = "UpdatePollIntervalSeconds")]
+    update_check_interval: u64,
+    #[serde(rename = "InventoryPollIntervalSeconds")]
+    inventory_check_interval: u64,
+    // A tenth of the interval if not set
+    #[serde(rename = "UpdatePollJitterSeconds")]
+    update_check_jitter: Option<u64>,
+    #[serde(rename = "InventoryPollJitterSeconds")]
+    inventory_check_jitter: Option<u64>,
+}
+
+impl Default for IntervalConf {
+    fn default() -> Self {
+        IntervalConf {
+            update_check_interval: 600,
+            inventory_check_interval: 1200,
+            update_check_jitter: None,
+            inventory_check_jitter: None,
+        }
+    }
+}
+
+struct Job {
+    event: Event,
+    // None for a one-shot job
+    interval: Option<Duration>,
+    // How long after it is started the job runs the first time
+    first: Duration,
+    // The most a run may be put off on top of the interval
+    jitter: Duration,
+    // Persist when the job last ran
+    persist: bool,
+    // When the job runs next, or None while it is stopped
+    next: Option<Instant>,
+}
+
+impl Job {
+    fn periodic(event: Event, interval: Duration, first: Duration, jitter: u64) -> Job {
+        Job {
+            event: event,
+            interval: Some(interval),
+            first: first,
+            jitter: Duration::from_secs(jitter),
+            persist: false,
+            next: None,
+        }
+    }
+
+    // The time until the next run, with the jitter added. Runs due right
+    // away stay so.
+    fn delay(&self, after: Duration) -> Duration {
+        if after == Duration::from_secs(0) || self.jitter == Duration::from_secs(0) {
+            return after;
+        }
+        let mut bytes = [0; 8];
+        if let Err(e) = openssl::rand::rand_bytes(&mut bytes) {
+            warn!("scheduler: Failed to get random bytes for the jitter: {}", e);
+            return after;
+        }
+        let jitter = u64::from_ne_bytes(bytes) % (self.jitter.as_millis() as u64 + 1);
+        after + Duration::from_millis(jitter)
+    }
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Remember that the job ran at the given time, if it is to be persisted.
+fn record_run(runs: &mut HashMap<String, u64>, store: &Option<DataStore>, name: &str, now: SystemTime) {
+    if let Some(store) = store {
+        runs.insert(name.to_string(), epoch_secs(now));
+        if let Err(e) = store.store_runs(runs) {
+            warn!("scheduler: Failed to store when {} ran: {}", name, e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: HashMap<String, Job>,
+    // The events fired, waiting for the state machine
+    queue: EventQueue,
+    // When the persisted jobs last ran, in seconds since the epoch
+    runs: HashMap<String, u64>,
+    store: Option<DataStore>,
+    // Set while the timer task runs
+    running: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    // Notified when events are fired, for JobEvents
+    fired: Condvar,
+    // Notified when the jobs change, for the timer task
+    changed: Notify,
+}
+
+#[derive(Clone)]
+pub struct Scheduler<C: Clock = SystemClock> {
+    shared: Arc<Shared>,
+    clock: C,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::with_clock(SystemClock {})
+    }
+
+    // The jobs of the client: authorize at once, send the inventory as soon
+    // as we are authorized, and check for an update an interval later.
+    pub fn from_conf(conf: IntervalConf) -> Scheduler {
+        let scheduler = Scheduler::new();
+        scheduler.add_periodic(AUTHORIZE, Event::AuthorizeAttempt, AUTHORIZE_INTERVAL, Duration::from_secs(0));
+        let interval = Duration::from_secs(conf.update_check_interval);
+        let jitter = conf.update_check_jitter.unwrap_or(conf.update_check_interval / 10);
+        scheduler.add(CHECK_UPDATE, Job::periodic(Event::CheckForUpdate, interval, interval, jitter));
+        let interval = Duration::from_secs(conf.inventory_check_interval);
+        let jitter = conf.inventory_check_jitter.unwrap_or(conf.inventory_check_interval / 10);
+        scheduler.add(
+            SEND_INVENTORY,
+            Job::periodic(Event::SendInventory, interval, Duration::from_secs(0), jitter),
+        );
+        scheduler.add_once(CHECK_UPDATE_ONCE, Event::CheckForUpdate);
+        scheduler
+    }
+}
+
+impl<C: Clock + Clone + Send + 'static> Scheduler<C> {
+    // A scheduler whose jobs run by the given clock.
+    pub fn with_clock(clock: C) -> Scheduler<C> {
+        Scheduler {
+            shared: Arc::default(),
+            clock: clock,
+        }
+    }
+
+    // Persist when the given jobs last ran in the store, and pick up when
+    // they ran before the restart.
+    pub fn with_store(self, store: DataStore, jobs: &[&str]) -> Scheduler<C> {
+        {
+            let mut state = self.lock();
+            state.runs = store.load_runs().unwrap_or_else(|e| {
+                warn!("scheduler: Failed to load when the jobs last ran: {}", e);
+                HashMap::new()
+            });
+            state.store = Some(store);
+            for name in jobs {
+                if let Some(job) = state.jobs.get_mut(*name) {
+                    job.persist = true;
+                }
+            }
+        }
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    fn add(&self, name: &str, job: Job) {
+        self.lock().jobs.insert(name.to_string(), job);
+    }
+
+    // Add a job which sends the event every interval, the first time the
+    // given time after it is started.
+    pub fn add_periodic(&self, name: &str, event: Event, interval: Duration, first: Duration) {
+        self.add(name, Job::periodic(event, interval, first, 0))
+    }
+
+    // Add a job which sends the event once, when rescheduled.
+    pub fn add_once(&self, name: &str, event: Event) {
+        self.add(
+            name,
+            Job {
+                event: event,
+                interval: None,
+                first: Duration::from_secs(0),
+                jitter: Duration::from_secs(0),
+                persist: false,
+                next: None,
+            },
+        )
+    }
+
+    // Change what is in a job, and wake up the timer task to take another
+    // look at the jobs.
+    fn update_job<F>(&self, name: &str, f: F) -> Result<(), &'static str>
+    where
+        F: FnOnce(&mut State, &str) -> Result<(), &'static str>,
+    {
+        let mut state = self.lock();
+        if !state.jobs.contains_key(name) {
+            return Err("Unknown job");
+        }
+        f(&mut state, name)?;
+        if !state.running && state.jobs.values().any(|job| job.next.is_some()) {
+            state.running = true;
+            runtime::spawn(run(self.shared.clone(), self.clock.clone()));
+        }
+        self.shared.fired.notify_all();
+        self.shared.changed.notify();
+        Ok(())
+    }
+
+    // Start the job. Starting a running job does nothing. A persisted job
+    // which has run before runs when its interval is up.
+    pub fn start_job(&self, name: &str) -> Result<(), &'static str> {
+        let clock = &self.clock;
+        self.update_job(name, |state, name| {
+            let job = state.jobs.get_mut(name).unwrap();
+            if job.next.is_some() {
+                return Ok(());
+            }
+            let first = match (job.persist, job.interval, state.runs.get(name)) {
+                (true, Some(interval), Some(&last)) => {
+                    let elapsed = epoch_secs(clock.wall()).saturating_sub(last);
+                    interval
+                        .checked_sub(Duration::from_secs(elapsed))
+                        .unwrap_or_else(|| Duration::from_secs(0))
+                }
+                _ => job.first,
+            };
+            debug!("scheduler: Starting {}, running it in {:?}", name, first);
+            job.next = Some(clock.now() + job.delay(first));
+            Ok(())
+        })
+    }
+
+    pub fn stop_job(&self, name: &str) -> Result<(), &'static str> {
+        self.update_job(name, |state, name| {
+            let job = state.jobs.get_mut(name).unwrap();
+            if job.next.take().is_some() {
+                debug!("scheduler: Stopped {}", name);
+            }
+            state.queue.drop_job(name);
+            Ok(())
+        })
+    }
+
+    // Run a periodic job every given time from now on. A one-shot job runs
+    // once, the given time from now, whether it was running or not.
+    pub fn reschedule(&self, name: &str, after: Duration) -> Result<(), &'static str> {
+        let clock = &self.clock;
+        self.update_job(name, |state, name| {
+            let job = state.jobs.get_mut(name).unwrap();
+            match job.interval {
+                Some(_) => job.interval = Some(after),
+                None => job.next = Some(clock.now()),
+            }
+            if job.next.is_some() {
+                debug!("scheduler: Rescheduled {}, running it in {:?}", name, after);
+                job.next = Some(clock.now() + job.delay(after));
+            }
+            Ok(())
+        })
+    }
+
+    // Send the event of a running job right away, i.e. when asked for on the
+    // daemon socket or by a signal. The job runs a full interval from now
+    // next, so that it does not run twice in a row.
+    pub fn trigger(&self, name: &str) -> Result<(), &'static str> {
+        let clock = &self.clock;
+        self.update_job(name, |state, name| {
+            let job = state.jobs.get_mut(name).unwrap();
+            if job.next.is_none() {
+                return Err("The job is not running");
+            }
+            job.next = job.interval.map(|interval| clock.now() + job.delay(interval));
+            let event = job.event.clone();
+            let persist = job.persist;
+            debug!("scheduler: Triggered {}", name);
+            state.queue.push(name, event);
+            if persist {
+                record_run(&mut state.runs, &state.store, name, clock.wall());
+            }
+            Ok(())
+        })
+    }
+
+    // Stop every job, which ends the timer task, and wakes up whoever waits
+    // for an event, i.e. on a shutdown.
+    pub fn stop(&self) {
+        let mut state = self.lock();
+        let State { jobs, queue, .. } = &mut *state;
+        for (name, job) in jobs.iter_mut() {
+            job.next = None;
+            queue.drop_job(name);
+        }
+        self.shared.fired.notify_all();
+        self.shared.changed.notify();
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.lock().queue.metrics()
+    }
+
+    // The events of the given jobs.
+    pub fn events(&self, names: &[&str]) -> JobEvents<C> {
+        JobEvents {
+            scheduler: self.clone(),
+            names: names.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+// The timer task: fire the jobs which are due, and wait until the next one
+// is, or until the jobs change.
+async fn run<C: Clock>(shared: Arc<Shared>, clock: C) {
+    loop {
+        let next = match fire(&shared, &clock) {
+            Some(next) => next,
+            None => return,
+        };
+        // A change while we were not waiting yet is not missed, as notify()
+        // leaves a permit for the next notified().
+        future::select(clock.delay_until(next), Box::pin(shared.changed.notified())).await;
+    }
+}
+
+// Fire the jobs which are due, and return when the next one is. Returns None,
+// and marks the timer task as ended, if there is none.
+fn fire<C: Clock>(shared: &Shared, clock: &C) -> Option<Instant> {
+    let mut state = shared.state.lock().unwrap();
+    let now = clock.now();
+    let State {
+        jobs,
+        queue,
+        runs,
+        store,
+        ..
+    } = &mut *state;
+    let mut any_fired = false;
+    for (name, job) in jobs.iter_mut() {
+        match job.next {
+            Some(next) if next <= now => {
+                debug!("scheduler: Sent {:?} event!", job.event);
+                queue.push(name, job.event.clone());
+                job.next = job.interval.map(|interval| now + job.delay(interval));
+                if job.persist {
+                    record_run(runs, store, name, clock.wall());
+                }
+                any_fired = true;
+            }
+            _ => {}
+        }
+    }
+    if any_fired {
+        shared.fired.notify_all();
+    }
+    let next = state.jobs.values().filter_map(|job| job.next).min();
+    if next.is_none() {
+        debug!("scheduler: No jobs left to run");
+        state.running = false;
+    }
+    next
+}
+
+// JobEvents are the events of a group of jobs.
+pub struct JobEvents<C: Clock = SystemClock> {
+    scheduler: Scheduler<C>,
+    names: Vec<String>,
+}
+
+impl<C: Clock + Clone + Send + 'static> EventProducer for JobEvents<C> {
+    fn start(&self) {
+        for name in &self.names {
+            let once = matches!(self.scheduler.lock().jobs.get(name), Some(Job { interval: None, .. }));
+            if !once {
+                self.scheduler.start_job(name).unwrap();
+            }
+        }
+    }
+
+    // Returns the next event of the jobs. If none are ready, it blocks, and
+    // once the jobs are all stopped it returns Event::None.
+    fn next(&self) -> Event {
+        let fired = &self.scheduler.shared.fired;
+        let mut state = self.scheduler.lock();
+        loop {
+            if let Some(event) = state.queue.pop(&self.names) {
+                return event;
+            }
+            let running = self
+                .names
+                .iter()
+                .any(|name| state.jobs.get(name).and_then(|job| job.next).is_some());
+            if !running {
+                return Event::None;
+            }
+            state = fired.wait(state).unwrap();
+        }
+    }
+
+    fn stop(&self) {
+        for name in &self.names {
+            self.scheduler.stop_job(name).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use std::thread;
+
+    fn hour() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn running(scheduler: &Scheduler) -> bool {
+        scheduler.lock().running
+    }
+
+    #[test]
+    fn test_trigger_resets_timer() {
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        scheduler.add_periodic(CHECK_UPDATE, Event::CheckForUpdate, hour(), hour());
+        let events = scheduler.events(&[CHECK_UPDATE]);
+        events.start();
+        clock.advance(hour() / 2);
+        scheduler.trigger(CHECK_UPDATE).unwrap();
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        // Not when the first hour is up, but a full interval after the
+        // triggered check
+        clock.advance(hour() / 2);
+        thread::sleep(Duration::from_millis(50));
+        assert!(scheduler.lock().queue.pop(&events.names).is_none());
+        clock.advance(hour() / 2);
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        events.stop();
+    }
+
+    #[test]
+    fn test_groups() {
+        let scheduler = Scheduler::new();
+        scheduler.add_periodic(AUTHORIZE, Event::AuthorizeAttempt, hour(), Duration::from_secs(0));
+        scheduler.add_periodic(SEND_INVENTORY, Event::SendInventory, hour(), Duration::from_secs(0));
+        let auth = scheduler.events(&[AUTHORIZE]);
+        let sync = scheduler.events(&[SEND_INVENTORY]);
+        sync.start();
+        auth.start();
+        // Starting again does not run the jobs again
+        auth.start();
+        match auth.next() {
+            Event::AuthorizeAttempt => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        match sync.next() {
+            Event::SendInventory => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        scheduler.trigger(AUTHORIZE).unwrap();
+        auth.next();
+        assert!(scheduler.lock().queue.pop(&auth.names).is_none());
+        assert_eq!(scheduler.trigger("bogus"), Err("Unknown job"));
+        auth.stop();
+        assert_eq!(scheduler.trigger(AUTHORIZE), Err("The job is not running"));
+        sync.stop();
+        for _ in 0..100 {
+            if !running(&scheduler) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The timer task never ended");
+    }
+
+    #[test]
+    fn test_stop_wakes_events() {
+        let scheduler = Scheduler::new();
+        scheduler.add_periodic(CHECK_UPDATE, Event::CheckForUpdate, hour(), hour());
+        let events = scheduler.events(&[CHECK_UPDATE]);
+        events.start();
+        let stopper = scheduler.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            stopper.stop();
+        });
+        match events.next() {
+            Event::None => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_once() {
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        scheduler.add_periodic(CHECK_UPDATE, Event::CheckForUpdate, hour(), hour());
+        scheduler.add_once(CHECK_UPDATE_ONCE, Event::CheckForUpdate);
+        let events = scheduler.events(&[CHECK_UPDATE, CHECK_UPDATE_ONCE]);
+        // Starting the group leaves the one-shot job alone
+        events.start();
+        assert_eq!(scheduler.lock().jobs[CHECK_UPDATE_ONCE].next, None);
+        scheduler.reschedule(CHECK_UPDATE_ONCE, hour() / 4).unwrap();
+        clock.advance(hour() / 4);
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        // It stops once it has run
+        assert_eq!(scheduler.lock().jobs[CHECK_UPDATE_ONCE].next, None);
+        assert_eq!(scheduler.trigger(CHECK_UPDATE_ONCE), Err("The job is not running"));
+        // The periodic job still runs when its hour is up
+        clock.advance(hour() * 3 / 4);
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        events.stop();
+    }
+
+    #[test]
+    fn test_reschedule() {
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        scheduler.add_periodic(SEND_INVENTORY, Event::SendInventory, hour(), hour());
+        let events = scheduler.events(&[SEND_INVENTORY]);
+        events.start();
+        // The timer task waits for the hour, and is woken up to run the job
+        // a minute from now instead, and every minute after that
+        thread::sleep(Duration::from_millis(50));
+        scheduler.reschedule(SEND_INVENTORY, Duration::from_secs(60)).unwrap();
+        for _ in 0..2 {
+            clock.advance(Duration::from_secs(60));
+            match events.next() {
+                Event::SendInventory => {}
+                e => panic!("Unexpected event: {:?}", e),
+            }
+        }
+        assert_eq!(scheduler.lock().jobs[SEND_INVENTORY].interval, Some(Duration::from_secs(60)));
+        assert_eq!(scheduler.reschedule("bogus", hour()), Err("Unknown job"));
+        events.stop();
+        // A stopped periodic job gets the new interval, but stays stopped
+        scheduler.reschedule(SEND_INVENTORY, hour()).unwrap();
+        assert_eq!(scheduler.lock().jobs[SEND_INVENTORY].next, None);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let job = Job::periodic(Event::CheckForUpdate, hour(), hour(), 60);
+        let delays: Vec<Duration> = (0..20).map(|_| job.delay(hour())).collect();
+        assert!(delays.iter().all(|&d| d >= hour() && d <= hour() + Duration::from_secs(60)));
+        assert!(delays.iter().any(|&d| d != delays[0]));
+        assert_eq!(job.delay(Duration::from_secs(0)), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_persisted_runs() {
+        let dir = std::env::temp_dir().join(format!("mender-scheduler-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let clock = VirtualClock::new();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        scheduler.add_periodic(SEND_INVENTORY, Event::SendInventory, hour(), Duration::from_secs(0));
+        let scheduler = scheduler.with_store(DataStore::new(&dir), &[SEND_INVENTORY]);
+        let events = scheduler.events(&[SEND_INVENTORY]);
+        // Never run before, so it runs at once
+        events.start();
+        events.next();
+        events.stop();
+        let runs = DataStore::new(&dir).load_runs().unwrap();
+        let ran = runs[SEND_INVENTORY];
+
+        // After a restart ten minutes later it runs when the hour is up
+        let mut runs = HashMap::new();
+        runs.insert(SEND_INVENTORY.to_string(), ran - 600);
+        DataStore::new(&dir).store_runs(&runs).unwrap();
+        let scheduler = Scheduler::with_clock(clock.clone());
+        scheduler.add_periodic(SEND_INVENTORY, Event::SendInventory, hour(), Duration::from_secs(0));
+        let scheduler = scheduler.with_store(DataStore::new(&dir), &[SEND_INVENTORY]);
+        scheduler.start_job(SEND_INVENTORY).unwrap();
+        let next = scheduler.lock().jobs[SEND_INVENTORY].next.unwrap();
+        assert_eq!(next - clock.now(), Duration::from_secs(50 * 60));
+        scheduler.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+// scripts module runs the state scripts around each state of the state machine.
+// Scripts are named <State>_<Action>_<NN>[_<description>], i.e.
+// Download_Enter_00 or ArtifactInstall_Leave_10_stop_app, and are run in the
+// order given by NN. The Idle, Sync and Download scripts are part of the
+// root filesystem, while the Artifact* scripts are delivered in the artifact.
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time;
+
+use super::clock::Clock;
+use super::config::{self, ConfigError};
+use super::ExternalState;
+
+pub const ROOTFS_SCRIPTS_DIR: &str = "/etc/mender/scripts";
+// The artifact scripts are stored here, in the data directory, during
+// Download.
+pub const ARTIFACT_SCRIPTS_DIR: &str = "scripts";
+
+// A script exiting with this code asks to be run again later.
+const RETRY_LATER: i32 = 21;
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+struct ScriptConf {
+    #[serde(rename = "StateScriptTimeoutSeconds")]
+    timeout: u64,
+    #[serde(rename = "StateScriptRetryTimeoutSeconds")]
+    retry_timeout: u64,
+    #[serde(rename = "StateScriptRetryIntervalSeconds")]
+    retry_interval: u64,
+}
+
+impl Default for ScriptConf {
+    fn default() -> Self {
+        ScriptConf {
+            timeout: 3600,
+            retry_timeout: 1800,
+            retry_interval: 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Enter,
+    Leave,
+    Error,
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(std::io::Error),
+    Failed(String, i32),
+    Killed(String),
+    Timeout(String),
+    RetryTimeout(String),
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "IO error: {}", e),
+            ScriptError::Failed(s, code) => write!(f, "Script {} exited with code {}", s, code),
+            ScriptError::Killed(s) => write!(f, "Script {} was killed by a signal", s),
+            ScriptError::Timeout(s) => write!(f, "Script {} timed out", s),
+            ScriptError::RetryTimeout(s) => write!(f, "Script {} kept asking to retry later", s),
+        }
+    }
+}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(error: std::io::Error) -> Self {
+        ScriptError::Io(error)
+    }
+}
+
+pub struct ScriptRunner {
+    rootfs_dir: PathBuf,
+    artifact_dir: PathBuf,
+    timeout: time::Duration,
+    retry_timeout: time::Duration,
+    retry_interval: time::Duration,
+}
+
+impl ScriptRunner {
+    // The runner of the daemon, keeping the artifact scripts in the given
+    // data directory.
+    pub fn new<P: AsRef<Path>>(data_dir: P) -> Result<ScriptRunner, ConfigError> {
+        Ok(ScriptRunner::from_conf(
+            PathBuf::from(ROOTFS_SCRIPTS_DIR),
+            data_dir.as_ref().join(ARTIFACT_SCRIPTS_DIR),
+            config::load()?,
+        ))
+    }
+
+    // A runner looking for the scripts in the given directories, with the
+    // default timeouts.
+    pub fn with_dirs<P: AsRef<Path>>(rootfs_dir: P, artifact_dir: P) -> ScriptRunner {
+        ScriptRunner::from_conf(rootfs_dir, artifact_dir, ScriptConf::default())
+    }
+
+    fn from_conf<P: AsRef<Path>>(rootfs_dir: P, artifact_dir: P, conf: ScriptConf) -> ScriptRunner {
+        ScriptRunner {
+            rootfs_dir: rootfs_dir.as_ref().to_path_buf(),
+            artifact_dir: artifact_dir.as_ref().to_path_buf(),
+            timeout: time::Duration::from_secs(conf.timeout),
+            retry_timeout: time::Duration::from_secs(conf.retry_timeout),
+            retry_interval: time::Duration::from_secs(conf.retry_interval),
+        }
+    }
+
+    // Replace the artifact scripts with the ones of the artifact being
+    // downloaded.
+    pub fn store_artifact_scripts(&self, scripts: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+        if let Err(e) = fs::remove_dir_all(&self.artifact_dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        fs::create_dir_all(&self.artifact_dir)?;
+        for (name, script) in scripts {
+            if parse_name(name).is_none() {
+                warn!("Ignoring the artifact script {}", name);
+                continue;
+            }
+            let path = self.artifact_dir.join(name);
+            fs::write(&path, script)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+
+    fn dir(&self, state: ExternalState) -> Option<&Path> {
+        match state {
+            ExternalState::Init => None,
+            ExternalState::Idle | ExternalState::Sync | ExternalState::Download => {
+                Some(&self.rootfs_dir)
+            }
+            _ => Some(&self.artifact_dir),
+        }
+    }
+
+    // Run all the scripts for the given state and action. Stops at the first
+    // failing script, except for Error scripts, which are all run and whose
+    // failures are only logged. Scripts asking to be retried are waited for
+    // on the clock.
+    pub fn run<C: Clock>(&self, state: ExternalState, action: Action, clock: &C) -> Result<(), ScriptError> {
+        let dir = match self.dir(state) {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        for script in scripts(dir, state, action)? {
+            debug!("Running the state script: {:?}", script);
+            match self.run_script(&script, clock) {
+                Ok(()) => {}
+                Err(e) if action == Action::Error => warn!("{}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn run_script<C: Clock>(&self, script: &Path, clock: &C) -> Result<(), ScriptError> {
+        let name = script.to_string_lossy().into_owned();
+        let start = clock.now();
+        loop {
+            match self.execute(script, &name)? {
+                0 => return Ok(()),
+                RETRY_LATER if clock.now() - start + self.retry_interval <= self.retry_timeout => {
+                    info!("Script {} asked to be retried in {:?}", name, self.retry_interval);
+                    clock.sleep(self.retry_interval);
+                }
+                RETRY_LATER => return Err(ScriptError::RetryTimeout(name)),
+                code => return Err(ScriptError::Failed(name, code)),
+            }
+        }
+    }
+
+    // Run the script to completion, and return its exit code. Kills the
+    // script if it runs for longer than the timeout.
+    fn execute(&self, script: &Path, name: &str) -> Result<i32, ScriptError> {
+        let mut child = Command::new(script).spawn()?;
+        let start = time::Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return status.code().ok_or_else(|| ScriptError::Killed(name.to_string()));
+            }
+            if start.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ScriptError::Timeout(name.to_string()));
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
+}
+
+// Find the scripts for the state and action, in the order they should run.
+fn scripts(dir: &Path, state: ExternalState, action: Action) -> Result<Vec<PathBuf>, ScriptError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(ScriptError::Io(e)),
+    };
+    let mut scripts = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match parse_name(&name) {
+            Some((s, a, number)) if s == format!("{:?}", state) && a == format!("{:?}", action) => {
+                scripts.push((number, name, entry.path()));
+            }
+            Some(_) => {}
+            None => debug!("Ignoring the file {} in {:?}", name, dir),
+        }
+    }
+    scripts.sort();
+    Ok(scripts.into_iter().map(|(_, _, path)| path).collect())
+}
+
+// Split <State>_<Action>_<NN>[_<description>] into its parts.
+fn parse_name(name: &str) -> Option<(&str, &str, u32)> {
+    let mut parts = name.splitn(4, '_');
+    let state = parts.next()?;
+    let action = parts.next()?;
+    let number = parts.next()?;
+    if number.len() != 2 {
+        return None;
+    }
+    Some((state, action, number.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+
+    fn script_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mender-scripts-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn add_script(dir: &Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    fn runner(dir: &Path) -> ScriptRunner {
+        ScriptRunner {
+            rootfs_dir: dir.to_path_buf(),
+            artifact_dir: dir.to_path_buf(),
+            timeout: time::Duration::from_secs(5),
+            retry_timeout: time::Duration::from_secs(1800),
+            retry_interval: time::Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(parse_name("Download_Enter_00"), Some(("Download", "Enter", 0)));
+        assert_eq!(
+            parse_name("ArtifactInstall_Leave_10_stop_app"),
+            Some(("ArtifactInstall", "Leave", 10))
+        );
+        assert_eq!(parse_name("ArtifactCommit_Error"), None);
+        assert_eq!(parse_name("Sync_Enter_1"), None);
+    }
+
+    #[test]
+    fn test_scripts_order() {
+        let dir = script_dir("order");
+        add_script(&dir, "Download_Enter_10", "exit 0");
+        add_script(&dir, "Download_Enter_02_first", "exit 0");
+        add_script(&dir, "Download_Leave_00", "exit 0");
+        add_script(&dir, "Idle_Enter_00", "exit 0");
+        let found = scripts(&dir, ExternalState::Download, Action::Enter).unwrap();
+        assert_eq!(found, vec![dir.join("Download_Enter_02_first"), dir.join("Download_Enter_10")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_failure_and_retry() {
+        let dir = script_dir("run");
+        let marker = dir.join("marker");
+        add_script(&dir, "ArtifactInstall_Enter_00", "exit 3");
+        add_script(
+            &dir,
+            "ArtifactCommit_Enter_00",
+            &format!("[ -e {0} ] && exit 0; touch {0}; exit 21", marker.display()),
+        );
+        add_script(&dir, "ArtifactReboot_Enter_00", "exit 21");
+        add_script(&dir, "ArtifactFailure_Error_00", "exit 1");
+        let runner = runner(&dir);
+        // Retrying for half an hour takes no time on a virtual clock
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        match runner.run(ExternalState::ArtifactInstall, Action::Enter, &clock) {
+            Err(ScriptError::Failed(_, 3)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(runner.run(ExternalState::ArtifactCommit, Action::Enter, &clock).is_ok());
+        assert_eq!(clock.now() - start, time::Duration::from_secs(60));
+        match runner.run(ExternalState::ArtifactReboot, Action::Enter, &clock) {
+            Err(ScriptError::RetryTimeout(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert_eq!(clock.now() - start, time::Duration::from_secs(60 + 1800));
+        assert!(runner.run(ExternalState::ArtifactFailure, Action::Error, &clock).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+// signals module handles the signals of the daemon. SIGUSR1 checks for an
+// update, and SIGUSR2 sends the inventory, right away, i.e.
+//
+//   kill -USR1 $(pidof mender-rust)
+//
+// SIGTERM and SIGINT stop the daemon. A state which is under way, like
+// writing the update to the inactive partition, is let finish, and the
+// daemon stops before entering the next one, which is persisted already. If
+// the daemon is only waiting, for the next event, at an update control pause
+// or for a maintenance window, the wait is woken up and it stops right away.
+// Either way it exits with 0.
+// Requests to the server which are safe to give up on, like an update check,
+// are cancelled right away, so that a hung connection does not hold us up. So
+// is the download of an update, which fails the deployment the way a crash
+// during the download would.
+// A second signal while waiting for a state to finish exits at once, with
+// 128 + the signal number, the way a shell reports a killed process.
+use log::{info, warn};
+use signal_hook::iterator::Signals;
+use std::cmp;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::clock::Clock;
+use super::runtime::Cancel;
+use super::scheduler::{self, Scheduler};
+
+#[derive(Default)]
+struct ShutdownState {
+    // The signal that asked us to stop
+    requested: Option<i32>,
+    // Set while the state machine is waiting at a checkpoint
+    waiting: bool,
+}
+
+// Shutdown is shared between the signal thread, which asks for the daemon to
+// stop, and the state machine, which stops when it is safe to.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<Mutex<ShutdownState>>,
+    cancel: Cancel,
+    // Called on a shutdown, to wake up what waits at a checkpoint
+    wakers: Arc<Mutex<Vec<Waker>>>,
+}
+
+type Waker = Box<dyn Fn() + Send>;
+
+// How long a sleep at a checkpoint goes without looking for a shutdown
+const SLEEP_SLICE: Duration = Duration::from_secs(1);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn request(&self, signal: i32) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.requested.is_some() {
+                warn!("Got signal {} again, exiting without finishing the current state", signal);
+                process::exit(128 + signal);
+            }
+            if state.waiting {
+                info!("Got signal {} while waiting, stopping", signal);
+            } else {
+                info!("Got signal {}, stopping after the current state", signal);
+            }
+            state.requested = Some(signal);
+        }
+        self.cancel.cancel();
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+
+    // Have wake called on a shutdown, to wake up a wait which the shutdown
+    // cannot reach otherwise.
+    pub fn on_request<F: Fn() + Send + 'static>(&self, wake: F) {
+        self.wakers.lock().unwrap().push(Box::new(wake));
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().unwrap().requested.is_some()
+    }
+
+    // What a shutdown cancels.
+    pub fn cancel(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    // Run f, which waits with the state persisted and nothing under way.
+    // A shutdown wakes it up, and makes what it waited for moot: returns
+    // None, without running f, if a shutdown is requested before, or while
+    // f waits.
+    pub fn checkpoint<F: FnOnce() -> R, R>(&self, f: F) -> Option<R> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.requested.is_some() {
+                return None;
+            }
+            state.waiting = true;
+        }
+        let r = f();
+        let mut state = self.state.lock().unwrap();
+        state.waiting = false;
+        match state.requested {
+            Some(_) => None,
+            None => Some(r),
+        }
+    }
+
+    // Sleep for the given time, or until a shutdown is requested.
+    pub fn sleep<C: Clock>(&self, clock: &C, duration: Duration) {
+        let deadline = clock.now() + duration;
+        while !self.is_requested() && clock.now() < deadline {
+            clock.sleep_until(cmp::min(deadline, clock.now() + SLEEP_SLICE));
+        }
+    }
+}
+
+// Handle the signals from a background thread.
+pub fn listen(scheduler: Scheduler, shutdown: Shutdown) -> std::io::Result<()> {
+    let signals = Signals::new(&[
+        signal_hook::SIGUSR1,
+        signal_hook::SIGUSR2,
+        signal_hook::SIGTERM,
+        signal_hook::SIGINT,
+    ])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let job = match signal {
+                signal_hook::SIGUSR1 => scheduler::CHECK_UPDATE,
+                signal_hook::SIGUSR2 => scheduler::SEND_INVENTORY,
+                signal_hook::SIGTERM | signal_hook::SIGINT => {
+                    shutdown.request(signal);
+                    continue;
+                }
+                _ => continue,
+            };
+            info!("Got signal {}, running {}", signal, job);
+            if let Err(e) = scheduler.trigger(job) {
+                warn!("Failed to run {}: {}", job, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::scheduler::IntervalConf;
+    use crate::{Event, EventProducer};
+    use std::process::Command;
+    use std::sync::mpsc;
+
+    fn kill(signal: &str) {
+        let status = Command::new("kill")
+            .args(&[signal, &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_signals() {
+        let scheduler = Scheduler::from_conf(IntervalConf::default());
+        let events = scheduler.events(&[scheduler::CHECK_UPDATE, scheduler::SEND_INVENTORY]);
+        let shutdown = Shutdown::new();
+        listen(scheduler, shutdown.clone()).unwrap();
+        events.start();
+        // The inventory is sent as soon as the jobs start
+        match events.next() {
+            Event::SendInventory => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        kill("-USR1");
+        match events.next() {
+            Event::CheckForUpdate => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        kill("-USR2");
+        match events.next() {
+            Event::SendInventory => {}
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        // Not waiting at a checkpoint, so this only asks to stop
+        kill("-TERM");
+        for _ in 0..100 {
+            if shutdown.is_requested() {
+                assert!(shutdown.cancel().is_cancelled());
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The shutdown was never requested");
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let shutdown = Shutdown::new();
+        let (woken, wait) = mpsc::channel();
+        shutdown.on_request(move || woken.send(()).unwrap());
+        assert_eq!(shutdown.checkpoint(|| 1), Some(1));
+        let requester = shutdown.clone();
+        let signal = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            requester.request(15);
+        });
+        // Woken up by the shutdown, with nothing to go on with
+        assert_eq!(shutdown.checkpoint(|| wait.recv().unwrap()), None);
+        signal.join().unwrap();
+        assert_eq!(shutdown.checkpoint(|| panic!("Run after the shutdown")), None::<()>);
+        // Sleeping is cut short as well
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        shutdown.sleep(&clock, Duration::from_secs(3600));
+        assert_eq!(clock.now(), start);
+    }
+}
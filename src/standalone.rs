@@ -0,0 +1,346 @@
+// standalone module installs artifacts without a Mender server, i.e. when
+// provisioning devices in the factory. 'install' writes the artifact to the
+// passive partition and sets it up for a trial boot, and after the reboot
+// 'commit' makes it permanent, or 'rollback' goes back to the old partition.
+// The standalone deployment is kept in the state store in between.
+//
+// Only rootfs-image artifacts, and deltas of them, are installed this way.
+// There are no update modules and no state scripts run without the daemon,
+// so artifacts with other payloads are refused.
+use log::info;
+use std::fs::File;
+use std::io::Read;
+
+use super::artifact::{self, ArtifactError, Header, Installer, RootfsImage};
+use super::bootflags::{BootEnv, BootState};
+use super::client::{self, ClientError};
+use super::datastore::{DataStore, StateData, StoreError};
+use super::partitions::PartitionError;
+use super::ExternalState;
+
+#[derive(Debug)]
+pub enum StandaloneError {
+    Io(std::io::Error),
+    Store(StoreError),
+    Partition(PartitionError),
+    Boot(&'static str),
+    Artifact(ClientError),
+    // There is a deployment in progress already
+    InProgress,
+    // There is no standalone deployment to commit or roll back
+    NoInstall,
+    // Committing before rebooting into the update
+    NotRunningUpdate,
+}
+
+impl std::fmt::Display for StandaloneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StandaloneError::Io(e) => write!(f, "IO error: {}", e),
+            StandaloneError::Store(e) => write!(f, "{}", e),
+            StandaloneError::Partition(e) => write!(f, "{}", e),
+            StandaloneError::Boot(e) => write!(f, "{}", e),
+            StandaloneError::Artifact(e) => write!(f, "{}", e),
+            StandaloneError::InProgress => write!(f, "A deployment is already in progress"),
+            StandaloneError::NoInstall => write!(f, "There is no standalone installation in progress"),
+            StandaloneError::NotRunningUpdate => {
+                write!(f, "Not running the update, reboot into it before committing")
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for StandaloneError {
+    fn from(error: std::io::Error) -> Self {
+        StandaloneError::Io(error)
+    }
+}
+
+impl From<StoreError> for StandaloneError {
+    fn from(error: StoreError) -> Self {
+        StandaloneError::Store(error)
+    }
+}
+
+impl From<PartitionError> for StandaloneError {
+    fn from(error: PartitionError) -> Self {
+        StandaloneError::Partition(error)
+    }
+}
+
+impl From<&'static str> for StandaloneError {
+    fn from(error: &'static str) -> Self {
+        StandaloneError::Boot(error)
+    }
+}
+
+impl From<ClientError> for StandaloneError {
+    fn from(error: ClientError) -> Self {
+        StandaloneError::Artifact(error)
+    }
+}
+
+impl From<ArtifactError> for StandaloneError {
+    fn from(error: ArtifactError) -> Self {
+        StandaloneError::Artifact(ClientError::from(error))
+    }
+}
+
+// Open the artifact at the given path or URL.
+fn open(source: &str) -> Result<Box<dyn Read>, StandaloneError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        Ok(Box::new(client::download(reqwest::Client::new().get(source), None)?))
+    } else {
+        Ok(Box::new(File::open(source)?))
+    }
+}
+
+// Whether the artifact can be installed without the daemon.
+fn check_payloads(header: &Header) -> Result<(), ArtifactError> {
+    for payload in &header.payloads {
+        if payload.payload_type != artifact::ROOTFS_IMAGE && payload.payload_type != artifact::ROOTFS_DELTA {
+            return Err(ArtifactError::Install(format!(
+                "Only rootfs-image artifacts can be installed standalone, {} has a {} payload",
+                header.artifact_name, payload.payload_type
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Stored persists the standalone deployment under the name in the header of
+// the artifact, before anything is written.
+struct Stored<'a> {
+    store: &'a DataStore,
+    data: Option<StateData>,
+    installer: RootfsImage,
+}
+
+impl<'a> Installer for Stored<'a> {
+    fn header(&mut self, header: &Header) -> Result<(), ArtifactError> {
+        check_payloads(header)?;
+        let data = StateData::standalone(&header.artifact_name, ExternalState::Download);
+        self.store
+            .store(&data)
+            .map_err(|e| ArtifactError::Install(e.to_string()))?;
+        self.data = Some(data);
+        self.installer.header(header)
+    }
+
+    fn file(&mut self, payload: usize, name: &str, size: u64, content: &mut dyn Read) -> Result<(), ArtifactError> {
+        self.installer.file(payload, name, size, content)
+    }
+
+    fn finish(&mut self) -> Result<(), ArtifactError> {
+        self.installer.finish()
+    }
+}
+
+// Install the artifact at the given path or URL, so that the next boot is a
+// trial boot of it.
+pub fn install<B: BootEnv>(env: &mut B, store: &DataStore, source: &str) -> Result<(), StandaloneError> {
+    if store.load()?.is_some() {
+        return Err(StandaloneError::InProgress);
+    }
+    let partitions = env.partitions()?;
+    let target = env.write_target(&partitions)?;
+    let passive = partitions.passive_number()?;
+    let mut installer = Stored {
+        store: store,
+        data: None,
+        installer: RootfsImage::new(&target).with_source(&partitions.active),
+    };
+    let installed = open(source)
+        .and_then(|artifact| Ok(artifact::read(artifact, &mut installer)?))
+        .and_then(|_| {
+            // Read, so the header has named the deployment
+            let mut data = installer.data.take().ok_or(StandaloneError::NoInstall)?;
+            data.state = ExternalState::ArtifactInstall;
+            store.store(&data)?;
+            BootState::read(env)?.mark_for_trial(env, passive)?;
+            Ok(data)
+        });
+    let mut data = match installed {
+        Ok(data) => data,
+        Err(e) => {
+            store.clear();
+            return Err(e);
+        }
+    };
+    data.state = ExternalState::ArtifactReboot;
+    store.store(&data)?;
+    info!("Installed {} from {} to {}, reboot to try it out", data.artifact_name, source, target);
+    Ok(())
+}
+
+fn load_standalone(store: &DataStore) -> Result<StateData, StandaloneError> {
+    match store.load()? {
+        Some(ref data) if data.standalone => Ok(data.clone()),
+        _ => Err(StandaloneError::NoInstall),
+    }
+}
+
+// Make the installed update permanent, once we are running it.
+pub fn commit<B: BootEnv>(env: &mut B, store: &DataStore) -> Result<(), StandaloneError> {
+    let data = load_standalone(store)?;
+    let mut boot_state = BootState::read(env)?;
+    let active = env.partitions()?.active_number()?;
+    if !boot_state.is_trial_boot() || boot_state.boot_part != active {
+        return Err(StandaloneError::NotRunningUpdate);
+    }
+    boot_state.commit(env)?;
+    store.clear();
+    info!("Committed {}", data.artifact_name);
+    Ok(())
+}
+
+// Go back to the partition which was running before the install. Returns
+// true if we are running the update, and have to reboot to finish.
+pub fn rollback<B: BootEnv>(env: &mut B, store: &DataStore) -> Result<bool, StandaloneError> {
+    let data = load_standalone(store)?;
+    let mut boot_state = BootState::read(env)?;
+    let partitions = env.partitions()?;
+    let (active, passive) = (partitions.active_number()?, partitions.passive_number()?);
+    let running_update = boot_state.is_trial_boot() && boot_state.boot_part == active;
+    let target = if running_update { passive } else { active };
+    boot_state.rollback(env, target)?;
+    store.clear();
+    info!("Rolled back {}, partition {} will be booted", data.artifact_name, target);
+    Ok(running_update)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootflags::BootFlagType;
+    use crate::partitions::Partitions;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    struct FakeBootEnv {
+        env: HashMap<String, String>,
+        active: u32,
+        // Where the passive partition is written
+        target: PathBuf,
+    }
+
+    impl FakeBootEnv {
+        fn new(target: PathBuf) -> FakeBootEnv {
+            let mut env = HashMap::new();
+            env.insert("mender_boot_part".to_string(), "2".to_string());
+            env.insert("bootcount".to_string(), "0".to_string());
+            env.insert("upgrade_available".to_string(), "0".to_string());
+            FakeBootEnv {
+                env: env,
+                active: 2,
+                target: target,
+            }
+        }
+
+        fn reboot_into(&mut self) {
+            self.active = self.env["mender_boot_part"].parse().unwrap();
+        }
+    }
+
+    impl BootEnv for FakeBootEnv {
+        fn get(&self, flag: BootFlagType) -> Result<String, &'static str> {
+            self.env.get(flag.name()).cloned().ok_or("The boot variable is not set")
+        }
+
+        fn set(&mut self, flags: &[(BootFlagType, String)]) -> Result<(), &'static str> {
+            for (flag, value) in flags {
+                self.env.insert(flag.name().to_string(), value.clone());
+            }
+            Ok(())
+        }
+
+        fn partitions(&self) -> Result<Partitions, PartitionError> {
+            Ok(Partitions {
+                active: format!("/dev/mmcblk0p{}", self.active),
+                passive: format!("/dev/mmcblk0p{}", 5 - self.active),
+            })
+        }
+
+        fn write_target(&self, _partitions: &Partitions) -> Result<String, PartitionError> {
+            Ok(self.target.to_string_lossy().into_owned())
+        }
+
+        fn reboot(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+    }
+
+    fn setup(name: &str) -> (PathBuf, DataStore, PathBuf, FakeBootEnv) {
+        let dir = std::env::temp_dir().join(format!("mender-standalone-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let artifact = dir.join("release-2.mender");
+        std::fs::write(&artifact, artifact::build("rootfs-image", &[("rootfs.ext4", b"rootfs")], &[])).unwrap();
+        std::fs::write(dir.join("passive"), b"").unwrap();
+        let env = FakeBootEnv::new(dir.join("passive"));
+        (dir.clone(), DataStore::new(&dir), artifact, env)
+    }
+
+    #[test]
+    fn test_install_and_commit() {
+        let (dir, store, artifact, mut env) = setup("commit");
+        install(&mut env, &store, artifact.to_str().unwrap()).unwrap();
+        let data = store.load().unwrap().unwrap();
+        assert!(data.standalone);
+        assert_eq!(data.artifact_name, "release-2");
+        assert_eq!(data.state, ExternalState::ArtifactReboot);
+        assert_eq!(env.env["mender_boot_part"], "3");
+        assert_eq!(env.env["upgrade_available"], "1");
+        assert_eq!(std::fs::read(&env.target).unwrap(), b"rootfs");
+        match install(&mut env, &store, artifact.to_str().unwrap()) {
+            Err(StandaloneError::InProgress) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match commit(&mut env, &store) {
+            Err(StandaloneError::NotRunningUpdate) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        env.reboot_into();
+        commit(&mut env, &store).unwrap();
+        assert_eq!(env.env["upgrade_available"], "0");
+        assert!(store.load().unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let (dir, store, artifact, mut env) = setup("rollback");
+        install(&mut env, &store, artifact.to_str().unwrap()).unwrap();
+        env.reboot_into();
+        assert!(rollback(&mut env, &store).unwrap());
+        assert_eq!(env.env["mender_boot_part"], "2");
+        assert_eq!(env.env["upgrade_available"], "0");
+        match rollback(&mut env, &store) {
+            Err(StandaloneError::NoInstall) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_module_artifact() {
+        let (dir, store, artifact, mut env) = setup("module");
+        std::fs::write(&artifact, artifact::build("app", &[("app", b"binary")], &[])).unwrap();
+        match install(&mut env, &store, artifact.to_str().unwrap()) {
+            Err(StandaloneError::Artifact(ref e)) if e.to_string().contains("app payload") => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(store.load().unwrap().is_none());
+        assert_eq!(env.env["upgrade_available"], "0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_missing_artifact() {
+        let (dir, store, artifact, mut env) = setup("missing");
+        assert!(install(&mut env, &store, &format!("{}.missing", artifact.display())).is_err());
+        assert!(store.load().unwrap().is_none());
+        assert_eq!(env.env["upgrade_available"], "0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
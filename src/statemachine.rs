@@ -0,0 +1,1930 @@
+// statemachine module runs the Mender client state machine. Everything it
+// talks to, the server, the boot environment, the event producers and the
+// clock, is handed to it, so that it can be driven by fakes in tests.
+use log::{debug, error, info, warn};
+use std::path::{Path, PathBuf};
+use std::time;
+
+use super::artifact::{self, ArtifactError, Header, Installer, RootfsImage};
+use super::bootflags::{BootEnv, BootState};
+use super::client::{self, ClientError, ServerApi, UpdateInfo};
+use super::clock::Clock;
+use super::datastore::{self, DataStore, StateData, StoreConf};
+use super::modules::{self, ModuleError, Reboot, UpdateModule, UpdateModules};
+use super::scripts::{self, ScriptRunner};
+use super::signals::Shutdown;
+use super::transitions::{self, Handler};
+use super::updatecontrol::{Action, ControlConf, UpdateControl};
+use super::windows::MaintenanceWindows;
+use super::{Event, EventProducer, ExternalState};
+
+// How long to wait for the system to go down before giving up on a reboot.
+const REBOOT_TIMEOUT: time::Duration = time::Duration::from_secs(600);
+
+struct InitState {}
+
+impl InitState {
+    // Resume the deployment in progress, if there is one. Otherwise check
+    // whether we just rebooted into a new update.
+    fn init<B: BootEnv>(
+        env: &B,
+        deployment: &mut Option<StateData>,
+        module: Option<UpdateModule>,
+    ) -> (ExternalState, Event) {
+        let data = match deployment {
+            Some(data) => data,
+            None => return InitState::check_boot_state(env),
+        };
+        data.retries += 1;
+        info!(
+            "Resuming deployment {} in state {:?} (attempt {})",
+            data.deployment_id, data.state, data.retries
+        );
+        match data.state {
+            // We crashed before touching the boot environment
+            ExternalState::Download => (ExternalState::ArtifactFailure, Event::None),
+            // Held back by a maintenance window, so wait for it again
+            ExternalState::ArtifactInstall | ExternalState::ArtifactReboot if data.paused => {
+                info!("The deployment is waiting for a maintenance window");
+                (data.state, Event::None)
+            }
+            // Stopped before the update was marked for a trial boot
+            ExternalState::ArtifactInstall if module.is_none() && ArtifactInstall::unmarked(env) => {
+                info!("The boot environment is untouched, installing the update again");
+                (ExternalState::ArtifactInstall, Event::None)
+            }
+            // The boot environment may be half way there, so restore it
+            ExternalState::ArtifactInstall => (ExternalState::ArtifactRollback, Event::None),
+            ExternalState::ArtifactReboot | ExternalState::ArtifactCommit if module.is_some() => {
+                VerifyReboot::verify_module(&module.unwrap())
+            }
+            ExternalState::ArtifactReboot | ExternalState::ArtifactCommit => match BootState::read(env) {
+                Ok(boot_state) if boot_state.is_trial_boot() => VerifyReboot::verify(env, &boot_state),
+                Ok(_) => {
+                    info!("The bootloader has rolled back the update");
+                    (ExternalState::ArtifactRollback, Event::None)
+                }
+                Err(e) => {
+                    info!("Unable to read the boot state: {}", e);
+                    (ExternalState::ArtifactRollback, Event::None)
+                }
+            },
+            ExternalState::ArtifactRollback => (ExternalState::ArtifactRollback, Event::None),
+            ExternalState::ArtifactRollbackReboot => match module {
+                Some(module) => ArtifactRollbackReboot::verify_module(&module),
+                None => ArtifactRollbackReboot::verify(env),
+            },
+            ExternalState::ArtifactFailure => (ExternalState::ArtifactFailure, Event::None),
+            ExternalState::Init | ExternalState::Idle | ExternalState::Sync => {
+                (ExternalState::Idle, Event::None)
+            }
+        }
+    }
+
+    // Where to go when a deployment has been through too many states. The
+    // first time we force it onto the rollback path, and if that loops as
+    // well, we give up on the deployment altogether, and report it failed.
+    fn break_loop(state: ExternalState, looped_before: bool) -> ExternalState {
+        if looped_before {
+            error!("The failure path is looping as well, giving up on the deployment");
+            return ExternalState::Idle;
+        }
+        transitions::failure_path(state)
+    }
+
+    // Check if we are in a committed, or un-committed partition. An
+    // un-committed partition means we just rebooted into a new update.
+    fn check_boot_state<B: BootEnv>(env: &B) -> (ExternalState, Event) {
+        match BootState::read(env) {
+            Ok(boot_state) if boot_state.is_trial_boot() => {
+                debug!("Entry into an uncommitted partition detected!");
+                VerifyReboot::verify(env, &boot_state)
+            }
+            Ok(_) => {
+                debug!("Entry into a committed partition detected!");
+                (ExternalState::Idle, Event::None)
+            }
+            Err(e) => {
+                debug!("Unable to read the boot state: {}. Did something go wrong?", e);
+                (ExternalState::Idle, Event::None)
+            }
+        }
+    }
+}
+
+struct Idle {}
+
+impl Idle {
+    fn wait_for_event(event_producer: &dyn EventProducer) -> (ExternalState, Event) {
+        match event_producer.next() {
+            Event::AuthorizeAttempt => (ExternalState::Sync, Event::AuthorizeAttempt),
+            Event::SendInventory => (ExternalState::Sync, Event::SendInventory),
+            Event::CheckForUpdate => (ExternalState::Sync, Event::CheckForUpdate),
+            _ => (ExternalState::Idle, Event::None), // Infinite loop
+        }
+    }
+}
+
+struct Sync {}
+
+impl Sync {
+    fn handle<S: ServerApi>(server: &mut S) -> (ExternalState, Event) {
+        // Try to authorize, if unsuccesful, wait for the next published authorization event.
+        if let Err(e) = server.authorize() {
+            info!("Authorization failed: {}", e);
+        }
+        (ExternalState::Idle, Event::None)
+    }
+
+    fn check_for_update<S: ServerApi>(server: &mut S) -> (ExternalState, Event) {
+        match server.check_for_update() {
+            Ok(Some(update_info)) => {
+                debug!("Yay, new update!");
+                debug!("{:#?}", update_info);
+                (ExternalState::Download, Event::DownloadUpdate(update_info))
+            }
+            Ok(None) => {
+                debug!("No new update available :(");
+                (ExternalState::Idle, Event::None)
+            }
+            Err(e) => {
+                info!("Sync: UpdateCheck: Error: {}", e);
+                (ExternalState::Idle, Event::None)
+            }
+        }
+    }
+
+    fn send_inventory<S: ServerApi>(server: &mut S) -> (ExternalState, Event) {
+        match server.send_inventory() {
+            Ok(()) => debug!("Inventory sent"),
+            Err(e) => debug!("Failed to send inventory: {}", e),
+        }
+        (ExternalState::Idle, Event::None)
+    }
+}
+
+struct Download {}
+
+impl Download {
+    // Write a rootfs-image straight to the passive partition, or apply a
+    // rootfs-image-delta to the active partition and write the result there.
+    fn rootfs<S: ServerApi, B: BootEnv>(
+        server: &mut S,
+        env: &B,
+        scripts: &ScriptRunner,
+        update_info: UpdateInfo,
+    ) -> (ExternalState, Event) {
+        let (source, target) = match env.partitions().and_then(|p| Ok((p.active.clone(), env.write_target(&p)?))) {
+            Ok(partitions) => partitions,
+            Err(e) => {
+                info!("Download: {}", e);
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+        };
+        let mut installer = RootfsImage::new(&target).with_source(&source);
+        Download::update(server, scripts, update_info, None, &mut installer)
+    }
+
+    // Download the update with the installer, which is the download of the
+    // module, if the update has one.
+    fn update<S: ServerApi>(
+        server: &mut S,
+        scripts: &ScriptRunner,
+        update_info: UpdateInfo,
+        module: Option<&UpdateModule>,
+        installer: &mut dyn Installer,
+    ) -> (ExternalState, Event) {
+        let mut installer = WithScripts {
+            scripts: scripts,
+            installer: installer,
+        };
+        match server.download_update(update_info, &mut installer) {
+            Ok(()) => (ExternalState::ArtifactInstall, Event::None),
+            Err(ref e) if e.is_aborted() => ArtifactAbort::abort(module),
+            Err(e) => {
+                info!("Download: Failed to download the update: {}", e);
+                (ExternalState::ArtifactFailure, Event::None)
+            }
+        }
+    }
+}
+
+// WithScripts stores the state scripts of the artifact, before handing the
+// artifact on to the installer.
+struct WithScripts<'a> {
+    scripts: &'a ScriptRunner,
+    installer: &'a mut dyn Installer,
+}
+
+impl<'a> Installer for WithScripts<'a> {
+    fn header(&mut self, header: &Header) -> Result<(), ArtifactError> {
+        self.scripts.store_artifact_scripts(&header.scripts)?;
+        self.installer.header(header)
+    }
+
+    fn file(&mut self, payload: usize, name: &str, size: u64, content: &mut dyn std::io::Read) -> Result<(), ArtifactError> {
+        self.installer.file(payload, name, size, content)
+    }
+
+    fn finish(&mut self) -> Result<(), ArtifactError> {
+        self.installer.finish()
+    }
+}
+
+struct ArtifactInstall {}
+
+impl ArtifactInstall {
+    fn install<B: BootEnv>(env: &mut B) -> (ExternalState, Event) {
+        let passive_num = match env.partitions().and_then(|p| p.passive_number()) {
+            Ok(num) => num,
+            Err(e) => {
+                info!("Install: Failed to find the passive partition: {}", e);
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+        };
+        let marked = BootState::read(env)
+            .and_then(|mut boot_state| boot_state.mark_for_trial(env, passive_num));
+        match marked {
+            Ok(()) => (ExternalState::ArtifactReboot, Event::None),
+            Err(e) => {
+                info!("Install: {}", e);
+                (ExternalState::ArtifactFailure, Event::None)
+            }
+        }
+    }
+
+    // Whether the bootloader still boots the running partition, with no
+    // update marked for a trial boot.
+    fn unmarked<B: BootEnv>(env: &B) -> bool {
+        let active = match env.partitions().and_then(|p| p.active_number()) {
+            Ok(num) => num,
+            Err(_) => return false,
+        };
+        match BootState::read(env) {
+            Ok(boot_state) => !boot_state.upgrade_available && boot_state.boot_part == active,
+            Err(_) => false,
+        }
+    }
+
+    // Point the bootloader back at the running partition, while the reboot
+    // into the update is held back.
+    fn unmark<B: BootEnv>(env: &mut B) -> Result<(), String> {
+        let active = env.partitions().and_then(|p| p.active_number()).map_err(|e| e.to_string())?;
+        BootState::read(env)
+            .and_then(|mut boot_state| boot_state.rollback(env, active))
+            .map_err(str::to_string)
+    }
+
+    // The module installs the update, and then tells whether it needs a
+    // reboot to finish. The status is reported at every interval while the
+    // module is installing, so that an abort on the server stops it.
+    fn install_module<S: ServerApi>(
+        server: &mut S,
+        module: &UpdateModule,
+        deployment: &Option<StateData>,
+        interval: std::time::Duration,
+    ) -> (ExternalState, Event) {
+        let installed = module.call_with_heartbeat("ArtifactInstall", interval, || {
+            report_status(server, deployment, "installing").is_ok()
+        });
+        match installed.and_then(|_| module.needs_reboot()) {
+            Ok(Reboot::No) => (ExternalState::ArtifactCommit, Event::None),
+            Ok(_) => (ExternalState::ArtifactReboot, Event::None),
+            // Part of the update may be installed already
+            Err(ModuleError::Aborted(_)) => {
+                info!("Install: The deployment was aborted on the server");
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+            Err(e) => {
+                info!("Install: {}", e);
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+        }
+    }
+}
+
+// VerifyReboot checks, after a reboot, whether the bootloader actually
+// started the partition the update was written to.
+struct VerifyReboot {}
+
+impl VerifyReboot {
+    fn verify<B: BootEnv>(env: &B, boot_state: &BootState) -> (ExternalState, Event) {
+        match env.partitions().and_then(|p| p.active_number()) {
+            Ok(active) if active == boot_state.boot_part => {
+                debug!("Running the updated partition {}", active);
+                (ExternalState::ArtifactCommit, Event::None)
+            }
+            Ok(active) => {
+                info!(
+                    "Expected to boot partition {}, but running {}",
+                    boot_state.boot_part, active
+                );
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+            Err(e) => {
+                info!("Failed to verify the reboot: {}", e);
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+        }
+    }
+
+    fn verify_module(module: &UpdateModule) -> (ExternalState, Event) {
+        match module.call("ArtifactVerifyReboot") {
+            Ok(_) => (ExternalState::ArtifactCommit, Event::None),
+            Err(e) => {
+                info!("Failed to verify the reboot: {}", e);
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+        }
+    }
+}
+
+struct ArtifactCommit {}
+
+impl ArtifactCommit {
+    fn commit<S: ServerApi, B: BootEnv>(
+        server: &mut S,
+        env: &mut B,
+        deployment: &Option<StateData>,
+    ) -> (ExternalState, Event) {
+        let committed = BootState::read(env).and_then(|mut boot_state| boot_state.commit(env));
+        if let Err(e) = committed {
+            info!("Commit: {}", e);
+            return (ExternalState::ArtifactRollback, Event::None);
+        }
+        ArtifactCommit::committed(server, deployment)
+    }
+
+    fn commit_module<S: ServerApi>(
+        server: &mut S,
+        module: &UpdateModule,
+        deployment: &Option<StateData>,
+    ) -> (ExternalState, Event) {
+        if let Err(e) = module.call("ArtifactCommit") {
+            info!("Commit: {}", e);
+            return (ExternalState::ArtifactRollback, Event::None);
+        }
+        ArtifactCommit::committed(server, deployment)
+    }
+
+    fn committed<S: ServerApi>(server: &mut S, deployment: &Option<StateData>) -> (ExternalState, Event) {
+        info!("Commit: The update is committed");
+        // Too late to abort now, the update is there to stay
+        let _ = report_status(server, deployment, "success");
+        (ExternalState::Idle, Event::None)
+    }
+}
+
+struct ArtifactRollback {}
+
+impl ArtifactRollback {
+    // Point the bootloader back at the partition which was running before
+    // the update. If we are currently running the update, we also need to
+    // reboot into the old partition.
+    fn rollback<B: BootEnv>(env: &mut B) -> (ExternalState, Event) {
+        let (mut boot_state, partitions) = match (BootState::read(env), env.partitions()) {
+            (Ok(boot_state), Ok(partitions)) => (boot_state, partitions),
+            (Err(e), _) => {
+                info!("Rollback: Unable to read the boot state: {}", e);
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+            (_, Err(e)) => {
+                info!("Rollback: Unable to detect the partitions: {}", e);
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+        };
+        let (active, passive) = match (partitions.active_number(), partitions.passive_number()) {
+            (Ok(active), Ok(passive)) => (active, passive),
+            _ => {
+                info!("Rollback: Unable to find the partition numbers");
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+        };
+        let running_update = boot_state.is_trial_boot() && boot_state.boot_part == active;
+        let target = if running_update { passive } else { active };
+        if let Err(e) = boot_state.rollback(env, target) {
+            info!("Rollback: {}", e);
+            return (ExternalState::ArtifactFailure, Event::None);
+        }
+        info!("Rollback: Partition {} will be booted", target);
+        if running_update {
+            (ExternalState::ArtifactRollbackReboot, Event::None)
+        } else {
+            (ExternalState::ArtifactFailure, Event::None)
+        }
+    }
+
+    // Modules which support it roll back themselves, and may need a reboot
+    // to finish, just like the install did.
+    fn rollback_module(module: &UpdateModule) -> (ExternalState, Event) {
+        match module.supports_rollback() {
+            Ok(true) => {}
+            Ok(false) => {
+                info!("Rollback: The update module does not support rolling back");
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+            Err(e) => {
+                info!("Rollback: {}", e);
+                return (ExternalState::ArtifactFailure, Event::None);
+            }
+        }
+        match module.call("ArtifactRollback").and_then(|_| module.needs_reboot()) {
+            Ok(Reboot::No) => (ExternalState::ArtifactFailure, Event::None),
+            Ok(_) => (ExternalState::ArtifactRollbackReboot, Event::None),
+            Err(e) => {
+                info!("Rollback: {}", e);
+                (ExternalState::ArtifactFailure, Event::None)
+            }
+        }
+    }
+}
+
+struct ArtifactRollbackReboot {}
+
+impl ArtifactRollbackReboot {
+    // Check that we are back on the old partition after resuming.
+    fn verify<B: BootEnv>(env: &B) -> (ExternalState, Event) {
+        let boot_part = BootState::read(env).map(|boot_state| boot_state.boot_part);
+        let active = env.partitions().and_then(|p| p.active_number());
+        match (boot_part, active) {
+            (Ok(boot_part), Ok(active)) if boot_part != active => {
+                info!("Still running partition {}, rebooting into {}", active, boot_part);
+                (ExternalState::ArtifactRollbackReboot, Event::None)
+            }
+            _ => (ExternalState::ArtifactFailure, Event::None),
+        }
+    }
+
+    fn verify_module(module: &UpdateModule) -> (ExternalState, Event) {
+        if let Err(e) = module.call("ArtifactVerifyRollbackReboot") {
+            info!("Failed to verify the rollback reboot: {}", e);
+        }
+        (ExternalState::ArtifactFailure, Event::None)
+    }
+}
+
+// ArtifactAbort stops a deployment which has been aborted on the server.
+// There is nothing to report. An update module cleans up what it has
+// downloaded; a partially written passive partition is not booted, and is
+// overwritten by the next update.
+struct ArtifactAbort {}
+
+impl ArtifactAbort {
+    fn abort(module: Option<&UpdateModule>) -> (ExternalState, Event) {
+        info!("Abort: The deployment was aborted on the server");
+        if let Some(module) = module {
+            module.cleanup();
+        }
+        (ExternalState::Idle, Event::None)
+    }
+
+    // The boot environment is already pointing at the update, so point it
+    // back at the running partition before giving up on the deployment.
+    fn revert<B: BootEnv>(env: &mut B) -> (ExternalState, Event) {
+        let active = match env.partitions().and_then(|p| p.active_number()) {
+            Ok(active) => active,
+            Err(e) => {
+                info!("Abort: Unable to find the active partition: {}", e);
+                return (ExternalState::ArtifactRollback, Event::None);
+            }
+        };
+        let reverted = BootState::read(env).and_then(|mut boot_state| boot_state.rollback(env, active));
+        match reverted {
+            Ok(()) => ArtifactAbort::abort(None),
+            Err(e) => {
+                info!("Abort: {}", e);
+                (ExternalState::ArtifactRollback, Event::None)
+            }
+        }
+    }
+}
+
+struct ArtifactFailure {}
+
+impl ArtifactFailure {
+    fn fail<S: ServerApi>(
+        server: &mut S,
+        module: Option<UpdateModule>,
+        deployment: &Option<StateData>,
+    ) -> (ExternalState, Event) {
+        info!("Failure: The update failed");
+        if let Some(module) = module {
+            if let Err(e) = module.call("ArtifactFailure") {
+                info!("Failure: {}", e);
+            }
+        }
+        let _ = report_status(server, deployment, "failure");
+        (ExternalState::Idle, Event::None)
+    }
+}
+
+// Report the status of the current deployment to the server, if we know
+// which deployment we are in. After a reboot the client has to authorize
+// again before it can report anything. Failing to report is only logged;
+// the only error returned is the server telling us the deployment has been
+// aborted.
+fn report_status<S: ServerApi>(
+    server: &mut S,
+    deployment: &Option<StateData>,
+    status: &str,
+) -> Result<(), ClientError> {
+    let deployment = match deployment {
+        Some(deployment) => deployment,
+        None => {
+            info!("Unknown deployment, not reporting the status: {}", status);
+            return Ok(());
+        }
+    };
+    if !server.is_authorized() {
+        Sync::handle(server);
+    }
+    if !server.is_authorized() {
+        info!("Not authorized, unable to report the status: {}", status);
+        return Ok(());
+    }
+    match server.report_deployment_status(&deployment.deployment_id, status) {
+        Ok(()) => debug!("Reported the deployment status: {}", status),
+        Err(ref e) if e.is_aborted() => return Err(ClientError::aborted()),
+        Err(e) => info!("Failed to report the deployment status: {}", e),
+    }
+    Ok(())
+}
+
+// Report a deployment which is given up on, because its failure path was
+// looping as well, as failed, with the reason in the deployment log.
+fn report_loop<S: ServerApi>(server: &mut S, deployment: &Option<StateData>) {
+    if report_status(server, deployment, "failure").is_err() {
+        return;
+    }
+    if let Some(ref data) = deployment {
+        let message = format!(
+            "The deployment was stuck in a loop of states, and so was its failure path. Gave up in {:?} after {} resumes.",
+            data.state, data.retries
+        );
+        if let Err(e) = server.upload_deployment_log(&data.deployment_id, &[message]) {
+            info!("Failed to upload the deployment log: {}", e);
+        }
+    }
+}
+
+// Reboot the device. Update modules either reboot it themselves in the given
+// state, or leave the reboot to the client.
+fn reboot<B: BootEnv>(env: &mut B, module: Option<UpdateModule>, state: &str) -> Result<(), String> {
+    if let Some(module) = module {
+        if module.needs_reboot().map_err(|e| e.to_string())? == Reboot::Yes {
+            return module.call(state).map(|_| ()).map_err(|e| e.to_string());
+        }
+    }
+    env.reboot().map_err(|e| e.to_string())
+}
+
+// Run the Leave scripts of the state we are leaving, or its Error scripts if
+// it failed, and the Enter scripts of the state we are entering. If one of
+// the Enter or Leave scripts fails, the Error scripts of that state are run,
+// and the state to go to instead is returned.
+fn run_state_scripts<C: Clock>(
+    runner: &ScriptRunner,
+    from: ExternalState,
+    to: ExternalState,
+    failed: bool,
+    clock: &C,
+) -> Option<ExternalState> {
+    let actions: &[(ExternalState, scripts::Action)] = if failed {
+        debug!("{:?} failed, running its Error scripts", from);
+        let _ = runner.run(from, scripts::Action::Error, clock);
+        &[(to, scripts::Action::Enter)]
+    } else {
+        &[(from, scripts::Action::Leave), (to, scripts::Action::Enter)]
+    };
+    for &(state, action) in actions {
+        if let Err(e) = runner.run(state, action, clock) {
+            warn!("{:?}_{:?} state script failed: {}", state, action, e);
+            let _ = runner.run(state, scripts::Action::Error, clock);
+            if let Some(next) = state.on_script_error(action) {
+                return Some(next);
+            }
+        }
+    }
+    None
+}
+
+// Report the given pause status, and persist that the deployment is paused,
+// so that it stays paused after a restart.
+fn pause<S: ServerApi>(
+    server: &mut S,
+    store: &DataStore,
+    deployment: &mut Option<StateData>,
+    update_control: &UpdateControl,
+    status: &str,
+) -> Result<(), ClientError> {
+    report_status(server, deployment, status)?;
+    if let Some(ref mut data) = deployment {
+        data.paused = true;
+        data.update_control = update_control.state();
+        if let Err(e) = store.store(data) {
+            warn!("Failed to store the state data: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// The outcome of a single step of the state machine.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    Continue,
+    // The reboot is under way, and the state machine should not go on.
+    Rebooting,
+    // A shutdown cut the state short, to be run again after the restart.
+    Stopped,
+}
+
+pub struct StateMachine<S, B, C> {
+    server: S,
+    boot_env: B,
+    auth_events: Box<dyn EventProducer>,
+    update_events: Box<dyn EventProducer>,
+    clock: C,
+    store: DataStore,
+    store_conf: StoreConf,
+    scripts: ScriptRunner,
+    modules: UpdateModules,
+    windows: MaintenanceWindows,
+    update_control: UpdateControl,
+    shutdown: Shutdown,
+    // How often to report the status while an update module installs
+    heartbeat: time::Duration,
+    cur_state: ExternalState,
+    cur_action: Event,
+    // The deployment currently being installed, if any
+    deployment: Option<StateData>,
+}
+
+impl<S: ServerApi, B: BootEnv, C: Clock> StateMachine<S, B, C> {
+    pub fn new(
+        server: S,
+        boot_env: B,
+        auth_events: Box<dyn EventProducer>,
+        update_events: Box<dyn EventProducer>,
+        clock: C,
+    ) -> Self {
+        StateMachine {
+            server: server,
+            boot_env: boot_env,
+            auth_events: auth_events,
+            update_events: update_events,
+            clock: clock,
+            store: DataStore::new(datastore::DEFAULT_DATA_DIR),
+            store_conf: StoreConf::default(),
+            scripts: ScriptRunner::with_dirs(
+                PathBuf::from(scripts::ROOTFS_SCRIPTS_DIR),
+                Path::new(datastore::DEFAULT_DATA_DIR).join(scripts::ARTIFACT_SCRIPTS_DIR),
+            ),
+            modules: UpdateModules::with_dirs(
+                PathBuf::from(modules::MODULES_DIR),
+                Path::new(datastore::DEFAULT_DATA_DIR).join(modules::MODULES_WORK_DIR),
+            ),
+            windows: MaintenanceWindows::default(),
+            update_control: UpdateControl::new(ControlConf::default()),
+            shutdown: Shutdown::new(),
+            heartbeat: client::HEARTBEAT_INTERVAL,
+            cur_state: ExternalState::Init,
+            cur_action: Event::Uninitialized,
+            deployment: None,
+        }
+    }
+
+    pub fn with_store(mut self, store: DataStore, store_conf: StoreConf) -> Self {
+        self.store = store;
+        self.store_conf = store_conf;
+        self
+    }
+
+    pub fn with_scripts(mut self, scripts: ScriptRunner) -> Self {
+        self.scripts = scripts;
+        self
+    }
+
+    pub fn with_modules(mut self, modules: UpdateModules) -> Self {
+        self.modules = modules;
+        self
+    }
+
+    pub fn with_windows(mut self, windows: MaintenanceWindows) -> Self {
+        self.windows = windows;
+        self
+    }
+
+    pub fn with_update_control(mut self, update_control: UpdateControl) -> Self {
+        self.update_control = update_control;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> ExternalState {
+        self.cur_state
+    }
+
+    pub fn run(&mut self) -> Result<(), &'static str> {
+        debug!("Running the state machine");
+        loop {
+            // Every state is persisted before it is entered, so this is a
+            // safe place to stop.
+            if self.shutdown.is_requested() {
+                self.stop();
+                return Ok(());
+            }
+            if self.step() == Step::Rebooting {
+                debug!("Rebooting...");
+                let shutdown = &self.shutdown;
+                let clock = &self.clock;
+                if shutdown.checkpoint(|| shutdown.sleep(clock, REBOOT_TIMEOUT)).is_none() {
+                    continue;
+                }
+                warn!("The system did not reboot in {:?}", REBOOT_TIMEOUT);
+                let next = transitions::failure_path(self.cur_state);
+                self.enter(next, Event::None);
+            }
+        }
+    }
+
+    // Run the handler of the current state, and move on to the next state.
+    pub fn step(&mut self) -> Step {
+        let action = std::mem::replace(&mut self.cur_action, Event::None);
+        let next = match transitions::lookup(self.cur_state, action.kind()) {
+            Ok(transition) => match self.handle(transition.handler, action) {
+                Some((state, action)) => transition.check(state).map(|state| (state, action)),
+                None if self.shutdown.is_requested() => return Step::Stopped,
+                None => return Step::Rebooting,
+            },
+            Err(e) => Err(e),
+        };
+        let (state, action) = match next {
+            Ok(next) => next,
+            Err(e) => {
+                error!("{}", e);
+                (transitions::failure_path(self.cur_state), Event::None)
+            }
+        };
+        self.enter(state, action);
+        Step::Continue
+    }
+
+    // Returns None if the handler started a reboot, or was stopped by a
+    // shutdown while it waited.
+    fn handle(&mut self, handler: Handler, action: Event) -> Option<(ExternalState, Event)> {
+        let next = match handler {
+            Handler::Init => {
+                self.deployment = match self.store.load() {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("Failed to load the state data: {}", e);
+                        None
+                    }
+                };
+                if let Some(ref data) = self.deployment {
+                    if data.standalone {
+                        warn!("A standalone installation is in progress, finish it with commit or rollback");
+                        self.deployment = None;
+                        return Some((ExternalState::Idle, Event::None));
+                    }
+                    self.update_control.restore(data.update_control.clone());
+                }
+                let module = self.update_module();
+                InitState::init(&self.boot_env, &mut self.deployment, module)
+            }
+            Handler::Idle if !self.server.is_authorized() => {
+                debug!("Client is not authorized, waiting for authorization event");
+                // Starting running events does nothing
+                self.update_events.stop();
+                self.auth_events.start();
+                self.shutdown.checkpoint(|| Idle::wait_for_event(self.auth_events.as_ref()))?
+            }
+            Handler::Idle => {
+                debug!("Client is authorized, waiting for update event");
+                self.shutdown.checkpoint(|| Idle::wait_for_event(self.update_events.as_ref()))?
+            }
+            Handler::Authorize => {
+                debug!("Sync: Authorization attempt");
+                let next = Sync::handle(&mut self.server);
+                if self.server.is_authorized() {
+                    debug!("Sync: client successfully authorized. Starting the update event producer");
+                    self.auth_events.stop();
+                    self.update_events.start();
+                }
+                next
+            }
+            Handler::CheckForUpdate => {
+                debug!("Sync: Check for update");
+                Sync::check_for_update(&mut self.server)
+            }
+            Handler::SendInventory => {
+                debug!("Sync: Sending inventory");
+                Sync::send_inventory(&mut self.server)
+            }
+            Handler::Download => match action {
+                Event::DownloadUpdate(update_info) => {
+                    debug!("Download: Downloading the new update d-_-b");
+                    match report_status(&mut self.server, &self.deployment, "downloading") {
+                        Ok(()) => match self.checked_module() {
+                            Ok(Some(module)) => Download::update(
+                                &mut self.server,
+                                &self.scripts,
+                                update_info,
+                                Some(&module),
+                                &mut module.download(),
+                            ),
+                            Ok(None) => Download::rootfs(&mut self.server, &self.boot_env, &self.scripts, update_info),
+                            Err(e) => {
+                                info!("Download: {}", e);
+                                (ExternalState::ArtifactFailure, Event::None)
+                            }
+                        },
+                        Err(_) => ArtifactAbort::abort(None),
+                    }
+                }
+                _ => (ExternalState::ArtifactFailure, Event::None),
+            },
+            Handler::Install => {
+                debug!("Install: Installing the update");
+                if self.wait_for_release(ExternalState::ArtifactInstall, "pause_before_installing")?
+                    == Action::Fail
+                {
+                    return Some((ExternalState::ArtifactFailure, Event::None));
+                }
+                let reported = self
+                    .hold("pause_before_installing")?
+                    .and_then(|_| report_status(&mut self.server, &self.deployment, "installing"));
+                match (reported, self.update_module()) {
+                    (Ok(()), Some(module)) => {
+                        ArtifactInstall::install_module(&mut self.server, &module, &self.deployment, self.heartbeat)
+                    }
+                    (Ok(()), None) => ArtifactInstall::install(&mut self.boot_env),
+                    (Err(_), module) => ArtifactAbort::abort(module.as_ref()),
+                }
+            }
+            Handler::Reboot => {
+                debug!("Reboot! rebooting...");
+                if self.wait_for_release(ExternalState::ArtifactReboot, "pause_before_rebooting")?
+                    == Action::Fail
+                {
+                    return Some((ExternalState::ArtifactRollback, Event::None));
+                }
+                let module = self.update_module();
+                // The update is not to be booted outside of the window, not
+                // even by a reboot while we hold
+                let unmarked = module.is_none() && !self.windows.is_open(self.clock.wall());
+                if unmarked {
+                    if let Err(e) = ArtifactInstall::unmark(&mut self.boot_env) {
+                        info!("Reboot: {}", e);
+                        return Some((ExternalState::ArtifactRollback, Event::None));
+                    }
+                }
+                let reported = self
+                    .hold("pause_before_rebooting")?
+                    .and_then(|_| report_status(&mut self.server, &self.deployment, "rebooting"));
+                match (reported, module.is_some()) {
+                    // The module has installed the update already
+                    (Err(_), true) => return Some((ExternalState::ArtifactRollback, Event::None)),
+                    (Err(_), false) => return Some(ArtifactAbort::revert(&mut self.boot_env)),
+                    (Ok(()), _) => {}
+                }
+                if unmarked && ArtifactInstall::install(&mut self.boot_env).0 != ExternalState::ArtifactReboot {
+                    return Some((ExternalState::ArtifactRollback, Event::None));
+                }
+                match reboot(&mut self.boot_env, module, "ArtifactReboot") {
+                    Ok(()) => return None,
+                    Err(e) => {
+                        info!("Reboot: {}", e);
+                        (ExternalState::ArtifactRollback, Event::None)
+                    }
+                }
+            }
+            Handler::Commit => {
+                debug!("Commit: Committing the update");
+                if self.wait_for_release(ExternalState::ArtifactCommit, "pause_before_committing")?
+                    == Action::Fail
+                {
+                    return Some((ExternalState::ArtifactRollback, Event::None));
+                }
+                match self.update_module() {
+                    Some(module) => ArtifactCommit::commit_module(&mut self.server, &module, &self.deployment),
+                    None => ArtifactCommit::commit(&mut self.server, &mut self.boot_env, &self.deployment),
+                }
+            }
+            Handler::Rollback => {
+                debug!("Rollback: Rolling back the update");
+                match self.update_module() {
+                    Some(module) => ArtifactRollback::rollback_module(&module),
+                    None => ArtifactRollback::rollback(&mut self.boot_env),
+                }
+            }
+            Handler::RollbackReboot => {
+                debug!("RollbackReboot: Rebooting into the old partition");
+                let module = self.update_module();
+                match reboot(&mut self.boot_env, module, "ArtifactRollbackReboot") {
+                    Ok(()) => return None,
+                    Err(e) => {
+                        info!("RollbackReboot: {}", e);
+                        (ExternalState::ArtifactFailure, Event::None)
+                    }
+                }
+            }
+            Handler::Failure => {
+                debug!("Failure: Cleaning up after the failed update");
+                let module = self.update_module();
+                ArtifactFailure::fail(&mut self.server, module, &self.deployment)
+            }
+        };
+        Some(next)
+    }
+
+    // The update module installing the current deployment, or None if it is
+    // a rootfs-image or a delta of one, which the client installs itself.
+    // Fails if the payload type cannot name a module.
+    fn checked_module(&self) -> Result<Option<UpdateModule>, ModuleError> {
+        let payload_type = match self.deployment.as_ref().and_then(|data| data.payload_types.first()) {
+            Some(payload_type) => payload_type,
+            None => return Ok(None),
+        };
+        if payload_type == artifact::ROOTFS_IMAGE || payload_type == artifact::ROOTFS_DELTA {
+            return Ok(None);
+        }
+        self.modules.module(payload_type).map(Some)
+    }
+
+    // The update module of the current deployment, past the download, which
+    // refuses payload types that cannot name a module.
+    fn update_module(&self) -> Option<UpdateModule> {
+        self.checked_module().ok().flatten()
+    }
+
+    // Wait at the update control pause point of the given state, reporting
+    // the given pause status if the deployment is paused there. Returns None
+    // if a shutdown stopped the wait, leaving the deployment paused.
+    fn wait_for_release(&mut self, state: ExternalState, status: &str) -> Option<Action> {
+        let update_control = self.update_control.clone();
+        let server = &mut self.server;
+        let store = &self.store;
+        let deployment = &mut self.deployment;
+        let clock = &self.clock;
+        let action = self.shutdown.checkpoint(|| {
+            update_control.wait(state, clock, || {
+                // An abort is picked up by the next status report
+                let _ = pause(server, store, deployment, &update_control, status);
+            })
+        })?;
+        debug!("Update control: {:?} before {:?}", action, state);
+        self.unpause();
+        Some(action)
+    }
+
+    // Hold the deployment until a maintenance window opens, reporting the
+    // given pause status meanwhile. The hold is persisted, so that it is
+    // picked up again after a restart. Returns None if a shutdown stopped it.
+    fn hold(&mut self, status: &str) -> Option<Result<(), ClientError>> {
+        let mut wait = match self.windows.wait_time(self.clock.wall()) {
+            Some(wait) => wait,
+            None => return Some(Ok(())),
+        };
+        if let Err(e) = pause(
+            &mut self.server,
+            &self.store,
+            &mut self.deployment,
+            &self.update_control,
+            status,
+        ) {
+            return Some(Err(e));
+        }
+        loop {
+            info!("Waiting {:?} for the maintenance window to open", wait);
+            let shutdown = &self.shutdown;
+            let clock = &self.clock;
+            shutdown.checkpoint(|| shutdown.sleep(clock, wait))?;
+            wait = match self.windows.wait_time(self.clock.wall()) {
+                Some(wait) => wait,
+                None => {
+                    self.unpause();
+                    return Some(Ok(()));
+                }
+            };
+        }
+    }
+
+    // Whether a standalone installation waits for its commit or rollback.
+    fn standalone_pending(&self) -> bool {
+        match self.store.load() {
+            Ok(Some(data)) => data.standalone,
+            _ => false,
+        }
+    }
+
+    // Persist where we are, and stop the event producers.
+    fn stop(&mut self) {
+        info!("Shutting down in {:?}", self.cur_state);
+        if let Some(ref mut data) = self.deployment {
+            data.update_control = self.update_control.state();
+            if let Err(e) = self.store.store(data) {
+                warn!("Failed to store the state data: {}", e);
+            }
+        }
+        self.auth_events.stop();
+        self.update_events.stop();
+    }
+
+    // Persist that the deployment is no longer paused, so that a reboot
+    // from here on is not mistaken for a restart during the pause.
+    fn unpause(&mut self) {
+        if let Some(ref mut data) = self.deployment {
+            if data.paused {
+                data.paused = false;
+                data.update_control = self.update_control.state();
+                if let Err(e) = self.store.store(data) {
+                    warn!("Failed to store the state data: {}", e);
+                }
+            }
+        }
+    }
+
+    // Run the state scripts, and persist the state before entering it.
+    fn enter(&mut self, state: ExternalState, action: Event) {
+        debug!("cur_state: {:?}, cur_event: {:?}", state, action);
+        // The standalone installation is finished by commit or rollback, and
+        // must not be overwritten by a deployment from the server meanwhile
+        let (state, action) = match action {
+            Event::DownloadUpdate(_) if self.standalone_pending() => {
+                warn!("A standalone installation is in progress, not taking the update from the server");
+                (ExternalState::Idle, Event::None)
+            }
+            action => (state, action),
+        };
+        if let Event::DownloadUpdate(ref update_info) = action {
+            self.deployment = Some(StateData::new(update_info, state));
+            if let Some(ref map) = update_info.update_control_map {
+                self.update_control.set_map(map.clone(), self.clock.wall());
+            }
+        }
+        // After a reboot we are leaving the reboot state we stored, not Init
+        let leaving = match (self.cur_state, &self.deployment) {
+            (ExternalState::Init, Some(data))
+                if data.state == ExternalState::ArtifactReboot
+                    || data.state == ExternalState::ArtifactRollbackReboot =>
+            {
+                data.state
+            }
+            _ => self.cur_state,
+        };
+        let (state, action) = if leaving != state {
+            let failed = transitions::is_failure(leaving, state);
+            match run_state_scripts(&self.scripts, leaving, state, failed, &self.clock) {
+                Some(next) => (next, Event::None),
+                None => (state, action),
+            }
+        } else {
+            (state, action)
+        };
+        // Persist every state of a deployment before entering it, so that
+        // we can resume from it after a reboot or a crash.
+        let (state, action) = match self.deployment {
+            Some(ref mut data) if state != ExternalState::Idle => {
+                let looped_before = data.loop_detected;
+                if data.enter(state, self.store_conf.max_state_count) {
+                    (state, action)
+                } else {
+                    let forced = InitState::break_loop(state, looped_before);
+                    warn!("State loop detected, forcing {:?}", forced);
+                    if forced == ExternalState::Idle {
+                        report_loop(&mut self.server, &self.deployment);
+                    } else {
+                        data.state = forced;
+                    }
+                    (forced, Event::None)
+                }
+            }
+            _ => (state, action),
+        };
+        match self.deployment {
+            Some(ref mut data) if state != ExternalState::Idle => {
+                data.update_control = self.update_control.state();
+                if let Err(e) = self.store.store(data) {
+                    warn!("Failed to store the state data: {}", e);
+                }
+            }
+            Some(_) => {
+                if let Some(module) = self.update_module() {
+                    module.cleanup();
+                }
+                self.store.clear();
+                self.update_control.clear();
+                self.deployment = None;
+            }
+            None => {}
+        }
+        self.cur_state = state;
+        self.cur_action = action;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bootflags::BootFlagType;
+    use crate::clock::VirtualClock;
+    use crate::client::ClientError;
+    use crate::partitions::{PartitionError, Partitions};
+    use crate::updatecontrol::UpdateControlMap;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, VecDeque};
+    use std::ops::Deref;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Server {
+        authorized: bool,
+        update: Option<UpdateInfo>,
+        statuses: Vec<String>,
+        // Abort the deployment when this status is reported
+        abort_at: Option<&'static str>,
+        // How many times that status is reported before the abort
+        reports_before_abort: usize,
+        // Abort the deployment while it is being downloaded
+        abort_download: bool,
+        // The artifact to hand to the installer, if any
+        artifact: Option<Vec<u8>>,
+        // The uploaded deployment log, shared with the test
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    #[derive(Clone)]
+    struct FakeServer(Rc<RefCell<Server>>);
+
+    impl ServerApi for FakeServer {
+        fn is_authorized(&self) -> bool {
+            self.0.borrow().authorized
+        }
+
+        fn authorize(&mut self) -> Result<(), ClientError> {
+            self.0.borrow_mut().authorized = true;
+            Ok(())
+        }
+
+        fn check_for_update(&mut self) -> Result<Option<UpdateInfo>, ClientError> {
+            Ok(self.0.borrow_mut().update.take())
+        }
+
+        fn send_inventory(&mut self) -> Result<(), ClientError> {
+            Ok(())
+        }
+
+        fn download_update(&mut self, _update_info: UpdateInfo, installer: &mut dyn Installer) -> Result<(), ClientError> {
+            let server = self.0.borrow();
+            if server.abort_download {
+                return Err(ClientError::aborted());
+            }
+            if let Some(ref artifact) = server.artifact {
+                artifact::read(&artifact[..], installer)?;
+            }
+            Ok(())
+        }
+
+        fn report_deployment_status(&mut self, _deployment_id: &str, status: &str) -> Result<(), ClientError> {
+            let mut server = self.0.borrow_mut();
+            server.statuses.push(status.to_string());
+            if server.abort_at == Some(status) {
+                if server.reports_before_abort == 0 {
+                    return Err(ClientError::aborted());
+                }
+                server.reports_before_abort -= 1;
+            }
+            Ok(())
+        }
+
+        fn upload_deployment_log(&mut self, _deployment_id: &str, messages: &[String]) -> Result<(), ClientError> {
+            self.0.borrow().log.borrow_mut().extend_from_slice(messages);
+            Ok(())
+        }
+    }
+
+    // The device: its bootloader environment, and the partition it runs.
+    struct Device {
+        env: HashMap<String, String>,
+        active: u32,
+        // Refuse to write the bootloader environment
+        fail_set: bool,
+        // Refuse to commit the update
+        fail_commit: bool,
+        // The update does not boot, so the bootloader rolls it back
+        broken_update: bool,
+        // Every partition the bootloader has been pointed at
+        boot_parts: Vec<String>,
+    }
+
+    impl Device {
+        fn new() -> Device {
+            let mut env = HashMap::new();
+            env.insert("mender_boot_part".to_string(), "2".to_string());
+            env.insert("bootcount".to_string(), "0".to_string());
+            env.insert("upgrade_available".to_string(), "0".to_string());
+            Device {
+                env: env,
+                active: 2,
+                fail_set: false,
+                fail_commit: false,
+                broken_update: false,
+                boot_parts: Vec::new(),
+            }
+        }
+
+        // Boot the device the way U-Boot would.
+        fn power_cycle(&mut self) {
+            if self.env["upgrade_available"] == "1" && self.broken_update {
+                self.env.insert("mender_boot_part".to_string(), self.active.to_string());
+                self.env.insert("upgrade_available".to_string(), "0".to_string());
+            }
+            self.active = self.env["mender_boot_part"].parse().unwrap();
+        }
+    }
+
+    #[derive(Clone)]
+    struct FakeBootEnv(Rc<RefCell<Device>>);
+
+    impl BootEnv for FakeBootEnv {
+        fn get(&self, flag: BootFlagType) -> Result<String, &'static str> {
+            self.0.borrow().env.get(flag.name()).cloned().ok_or("The boot variable is not set")
+        }
+
+        fn set(&mut self, flags: &[(BootFlagType, String)]) -> Result<(), &'static str> {
+            let mut device = self.0.borrow_mut();
+            // A commit clears upgrade_available, and leaves the boot
+            // partition alone
+            let written = |name| flags.iter().find(|(flag, _)| flag.name() == name).map(|(_, value)| value.as_str());
+            let committing = written("upgrade_available") == Some("0") && written("mender_boot_part").is_none();
+            if device.fail_set || (device.fail_commit && committing) {
+                return Err("Failed to set the firmware environment");
+            }
+            for (flag, value) in flags {
+                if let BootFlagType::BootPartition = flag {
+                    device.boot_parts.push(value.clone());
+                }
+                device.env.insert(flag.name().to_string(), value.clone());
+            }
+            Ok(())
+        }
+
+        fn partitions(&self) -> Result<Partitions, PartitionError> {
+            let active = self.0.borrow().active;
+            Ok(Partitions {
+                active: format!("/dev/mmcblk0p{}", active),
+                passive: format!("/dev/mmcblk0p{}", 5 - active),
+            })
+        }
+
+        fn write_target(&self, partitions: &Partitions) -> Result<String, PartitionError> {
+            Ok(partitions.passive.clone())
+        }
+
+        fn reboot(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+    }
+
+    // The events, and whether the producer is stopped.
+    struct FakeEvents(Rc<RefCell<VecDeque<Event>>>, Rc<Cell<bool>>);
+
+    impl FakeEvents {
+        fn new(events: Rc<RefCell<VecDeque<Event>>>) -> FakeEvents {
+            FakeEvents(events, Rc::new(Cell::new(true)))
+        }
+    }
+
+    impl EventProducer for FakeEvents {
+        fn start(&self) {
+            self.1.set(false);
+        }
+
+        fn next(&self) -> Event {
+            self.0.borrow_mut().pop_front().unwrap_or(Event::None)
+        }
+
+        fn stop(&self) {
+            self.1.set(true);
+        }
+    }
+
+    // The clock only moves when someone sleeps.
+    fn clock_at(s: &str) -> VirtualClock {
+        VirtualClock::at(chrono::DateTime::parse_from_rfc3339(s).unwrap().into())
+    }
+
+    // Open on Wednesdays from 02:00 to 04:00 UTC
+    const WEDNESDAY_NIGHTS: &str =
+        r#"{"MaintenanceWindows": [{"Days": ["Wed"], "Start": "02:00", "End": "04:00"}]}"#;
+
+    fn windows(json: &str) -> MaintenanceWindows {
+        MaintenanceWindows::from_conf(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    fn update_info() -> UpdateInfo {
+        serde_json::from_str(
+            r#"{
+                "id": "w81s4fae-7dec-11d0-a765-00a0c91e6bf6",
+                "artifact": {
+                    "artifact_name": "release-2",
+                    "source": {"uri": "https://aws.my_update_bucket.com/image_123", "expire": "2016-03-11T13:03:17.063493443Z"},
+                    "device_types_compatible": ["beaglebone"],
+                    "payload_types": ["rootfs-image"]
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    // Everything a deployment runs against.
+    struct Setup {
+        server: Server,
+        device: Device,
+        clock: VirtualClock,
+        window_conf: &'static str,
+        update_control: UpdateControl,
+        // The update module installing the "app" payloads
+        module: Option<String>,
+        heartbeat: time::Duration,
+        // The state to stop the daemon in, and restart it
+        stop_in: Option<ExternalState>,
+        // The state data the daemon finds when it starts
+        stored: Option<StateData>,
+    }
+
+    impl Setup {
+        fn new(server: Server, device: Device) -> Setup {
+            let mut server = server;
+            server.update = Some(update_info());
+            Setup {
+                server: server,
+                device: device,
+                clock: clock_at("2020-03-04T03:00:00Z"),
+                window_conf: "{}",
+                update_control: UpdateControl::new(ControlConf::default()),
+                module: None,
+                heartbeat: client::HEARTBEAT_INTERVAL,
+                stop_in: None,
+                stored: None,
+            }
+        }
+
+        // Deploy an "app" artifact with the app module, answering the
+        // NeedsArtifactReboot query with reboot, and failing in the given
+        // states.
+        fn with_module(reboot: &str, fail: &str) -> Setup {
+            let mut setup = Setup::new(Server::default(), Device::new());
+            let update = setup.server.update.as_mut().unwrap();
+            update.artifact.payload_types = Some(vec!["app".to_string()]);
+            setup.server.artifact = Some(artifact::build(
+                "app",
+                &[("app", b"binary")],
+                &[("ArtifactCommit_Enter_00", b"#!/bin/sh\necho ArtifactCommit_Enter >> $(dirname $0)/../log\n")],
+            ));
+            setup.module = Some(format!("REBOOT={}\nFAIL=\"{}\"\n{}", reboot, fail, APP_MODULE));
+            setup
+        }
+    }
+
+    // The directory of a test, removed when the test is done with it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("mender-statemachine-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // The setup put on the device, with the directory of the daemon.
+    struct Testbed {
+        server: FakeServer,
+        device: Rc<RefCell<Device>>,
+        dir: TempDir,
+        clock: VirtualClock,
+        window_conf: &'static str,
+        update_control: UpdateControl,
+        heartbeat: time::Duration,
+    }
+
+    impl Testbed {
+        fn new(name: &str, setup: Setup) -> Testbed {
+            let dir = TempDir::new(name);
+            if let Some(ref module) = setup.module {
+                std::fs::create_dir_all(dir.join("modules")).unwrap();
+                std::fs::write(dir.join("modules/app"), format!("#!/bin/sh\n{}\n", module)).unwrap();
+                std::fs::set_permissions(dir.join("modules/app"), std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            if let Some(ref data) = setup.stored {
+                DataStore::new(&dir).store(data).unwrap();
+            }
+            Testbed {
+                server: FakeServer(Rc::new(RefCell::new(setup.server))),
+                device: Rc::new(RefCell::new(setup.device)),
+                dir: dir,
+                clock: setup.clock,
+                window_conf: setup.window_conf,
+                update_control: setup.update_control,
+                heartbeat: setup.heartbeat,
+            }
+        }
+
+        // Start the daemon, reading the update events from the given
+        // producer.
+        fn start(&self, update: FakeEvents, shutdown: &Shutdown) -> StateMachine<FakeServer, FakeBootEnv, VirtualClock> {
+            let auth = Rc::new(RefCell::new(VecDeque::from(vec![Event::AuthorizeAttempt])));
+            let mut sm = StateMachine::new(
+                self.server.clone(),
+                FakeBootEnv(self.device.clone()),
+                Box::new(FakeEvents::new(auth)),
+                Box::new(update),
+                self.clock.clone(),
+            )
+            .with_store(DataStore::new(&self.dir), StoreConf::default())
+            .with_scripts(ScriptRunner::with_dirs(self.dir.join("scripts"), self.dir.join("scripts")))
+            .with_modules(UpdateModules::with_dirs(self.dir.join("modules"), self.dir.join("work")))
+            .with_windows(windows(self.window_conf))
+            .with_update_control(self.update_control.clone())
+            .with_shutdown(shutdown.clone());
+            sm.heartbeat = self.heartbeat;
+            sm
+        }
+
+        fn statuses(&self) -> Vec<String> {
+            self.server.0.borrow().statuses.clone()
+        }
+
+        fn stored(&self) -> Option<StateData> {
+            DataStore::new(&self.dir).load().unwrap()
+        }
+    }
+
+    fn deploy(name: &str, server: Server, device: Device) -> (Vec<String>, Rc<RefCell<Device>>, TempDir) {
+        deploy_with(name, Setup::new(server, device))
+    }
+
+    // Run a deployment on the device until the client is back in Idle with
+    // nothing left to do, rebooting the device whenever the client asks to.
+    fn deploy_with(name: &str, setup: Setup) -> (Vec<String>, Rc<RefCell<Device>>, TempDir) {
+        let mut stop_in = setup.stop_in;
+        let testbed = Testbed::new(name, setup);
+        let mut boots = 0;
+        'boot: loop {
+            boots += 1;
+            assert!(boots <= 3, "Too many reboots");
+            testbed.server.0.borrow_mut().authorized = false;
+            let update = Rc::new(RefCell::new(VecDeque::from(vec![Event::CheckForUpdate])));
+            let shutdown = Shutdown::new();
+            let mut sm = testbed.start(FakeEvents::new(update.clone()), &shutdown);
+            for _ in 0..100 {
+                if stop_in == Some(sm.state()) {
+                    // Restart the daemon, without rebooting
+                    stop_in = None;
+                    shutdown.request(15);
+                    sm.run().unwrap();
+                    continue 'boot;
+                }
+                if sm.step() == Step::Rebooting {
+                    testbed.device.borrow_mut().power_cycle();
+                    continue 'boot;
+                }
+                if sm.state() == ExternalState::Idle && update.borrow().is_empty() {
+                    return (testbed.statuses(), testbed.device.clone(), testbed.dir);
+                }
+            }
+            panic!("The state machine never settled in Idle");
+        }
+    }
+
+    #[test]
+    fn test_successful_deployment() {
+        let (statuses, device, dir) = deploy("success", Server::default(), Device::new());
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "success"]);
+        let device = device.borrow();
+        assert_eq!(device.active, 3);
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_failed_install() {
+        let mut device = Device::new();
+        device.fail_set = true;
+        let (statuses, device, _) = deploy("install", Server::default(), device);
+        assert_eq!(statuses, vec!["downloading", "installing", "failure"]);
+        assert_eq!(device.borrow().active, 2);
+    }
+
+    #[test]
+    fn test_bootloader_rollback() {
+        let mut device = Device::new();
+        device.broken_update = true;
+        let (statuses, device, _) = deploy("bootloader", Server::default(), device);
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "failure"]);
+        let device = device.borrow();
+        assert_eq!(device.active, 2);
+        assert_eq!(device.env["mender_boot_part"], "2");
+    }
+
+    #[test]
+    fn test_failed_commit() {
+        let mut device = Device::new();
+        device.fail_commit = true;
+        let (statuses, device, dir) = deploy("commit", Server::default(), device);
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "failure"]);
+        let device = device.borrow();
+        assert_eq!(device.active, 2);
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_failure_path_loop() {
+        // Resumed once too often on the failure path it was forced onto
+        let mut data = StateData::new(&update_info(), ExternalState::ArtifactRollback);
+        data.loop_detected = true;
+        data.state_count = StoreConf::default().max_state_count;
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.stored = Some(data);
+        let testbed = Testbed::new("loop", setup);
+        let mut sm = testbed.start(FakeEvents::new(Rc::default()), &Shutdown::new());
+        sm.step();
+        assert_eq!(sm.state(), ExternalState::Idle);
+        assert_eq!(testbed.statuses(), vec!["failure"]);
+        let server = testbed.server.0.borrow();
+        let log = server.log.borrow();
+        assert_eq!(log.len(), 1);
+        assert!(log[0].contains("loop"), "{:?}", log);
+        assert!(testbed.stored().is_none());
+    }
+
+    #[test]
+    fn test_aborted_download() {
+        let mut server = Server::default();
+        server.abort_download = true;
+        let (statuses, device, dir) = deploy("abort-download", server, Device::new());
+        assert_eq!(statuses, vec!["downloading"]);
+        let device = device.borrow();
+        assert_eq!(device.env["mender_boot_part"], "2");
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_aborted_before_install() {
+        let mut server = Server::default();
+        server.abort_at = Some("installing");
+        let (statuses, device, _) = deploy("abort-install", server, Device::new());
+        assert_eq!(statuses, vec!["downloading", "installing"]);
+        assert_eq!(device.borrow().env["upgrade_available"], "0");
+    }
+
+    #[test]
+    fn test_aborted_before_reboot() {
+        let mut server = Server::default();
+        server.abort_at = Some("rebooting");
+        let (statuses, device, dir) = deploy("abort-reboot", server, Device::new());
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting"]);
+        let device = device.borrow();
+        assert_eq!(device.active, 2);
+        assert_eq!(device.env["mender_boot_part"], "2");
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_aborted_module_download() {
+        let mut setup = Setup::with_module("No", "");
+        setup.server.abort_at = Some("installing");
+        let (statuses, _, dir) = deploy_with("abort-module-download", setup);
+        assert_eq!(statuses, vec!["downloading", "installing"]);
+        assert_eq!(module_log(&dir), vec!["Download", "Cleanup"]);
+        assert!(!dir.join("work/payloads").exists());
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_aborted_module_install() {
+        let mut setup = Setup::with_module("No", "");
+        setup.module = Some(format!(
+            "REBOOT=No\n{}\n[ \"$1\" = ArtifactInstall ] && sleep 10\nexit 0",
+            APP_MODULE
+        ));
+        setup.server.abort_at = Some("installing");
+        setup.server.reports_before_abort = 1;
+        setup.heartbeat = time::Duration::from_secs(0);
+        let start = time::Instant::now();
+        let (statuses, _, dir) = deploy_with("abort-module-install", setup);
+        // Stopped by the heartbeat, and rolled back
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+        assert_eq!(statuses, vec!["downloading", "installing", "installing", "failure"]);
+        let log = module_log(&dir);
+        assert!(log.contains(&"ArtifactRollback".to_string()), "{:?}", log);
+        assert_eq!(log.last().unwrap(), "Cleanup");
+        assert!(!dir.join("work/payloads").exists());
+    }
+
+    // Logs the states it is called in next to the state scripts, and fails
+    // the ones listed in $FAIL.
+    const APP_MODULE: &str = r#"
+        echo "$1" >> $(dirname $0)/../log
+        case " $FAIL " in *" $1 "*) exit 1 ;; esac
+        case "$1" in
+        NeedsArtifactReboot) echo "$REBOOT" ;;
+        SupportsRollback) echo Yes ;;
+        esac
+    "#;
+
+    fn module_log(dir: &Path) -> Vec<String> {
+        let log = std::fs::read_to_string(dir.join("log")).unwrap();
+        log.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_update_module() {
+        let (statuses, device, dir) = deploy_with("module", Setup::with_module("No", ""));
+        assert_eq!(statuses, vec!["downloading", "installing", "success"]);
+        assert_eq!(
+            module_log(&dir),
+            vec!["Download", "ArtifactInstall", "NeedsArtifactReboot", "ArtifactCommit_Enter", "ArtifactCommit", "Cleanup"]
+        );
+        // The stored files are gone with the tree
+        assert!(!dir.join("work/payloads").exists());
+        let device = device.borrow();
+        assert_eq!(device.active, 2);
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_payload_type() {
+        let mut setup = Setup::with_module("No", "");
+        let update = setup.server.update.as_mut().unwrap();
+        update.artifact.payload_types = Some(vec!["../modules/app".to_string()]);
+        let (statuses, _, dir) = deploy_with("payload-type", setup);
+        assert_eq!(statuses, vec!["downloading", "failure"]);
+        // The module is never run
+        assert!(!dir.join("log").exists());
+    }
+
+    #[test]
+    fn test_failed_install_error_scripts() {
+        let mut setup = Setup::with_module("No", "ArtifactInstall");
+        setup.server.artifact = Some(artifact::build(
+            "app",
+            &[("app", b"binary")],
+            &[
+                ("ArtifactInstall_Leave_00", b"#!/bin/sh\ntouch $(dirname $0)/../install-left\n"),
+                ("ArtifactInstall_Error_00", b"#!/bin/sh\ntouch $(dirname $0)/../install-failed\n"),
+            ],
+        ));
+        let (statuses, _, dir) = deploy_with("error-scripts", setup);
+        assert_eq!(statuses, vec!["downloading", "installing", "failure"]);
+        // Instead of the Leave scripts
+        assert!(dir.join("install-failed").exists());
+        assert!(!dir.join("install-left").exists());
+    }
+
+    #[test]
+    fn test_update_module_rollback() {
+        let setup = Setup::with_module("Yes", "ArtifactVerifyReboot");
+        let (statuses, device, dir) = deploy_with("module-rollback", setup);
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "failure"]);
+        assert_eq!(
+            module_log(&dir),
+            vec![
+                "Download",
+                "ArtifactInstall",
+                "NeedsArtifactReboot",
+                "NeedsArtifactReboot",
+                "ArtifactReboot",
+                "ArtifactVerifyReboot",
+                "SupportsRollback",
+                "ArtifactRollback",
+                "NeedsArtifactReboot",
+                "NeedsArtifactReboot",
+                "ArtifactRollbackReboot",
+                "ArtifactVerifyRollbackReboot",
+                "ArtifactFailure",
+                "Cleanup"
+            ]
+        );
+        // The boot environment is left alone
+        assert_eq!(device.borrow().env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_hold_for_maintenance_window() {
+        let clock = clock_at("2020-03-03T12:00:00Z");
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.clock = clock.clone();
+        setup.window_conf = WEDNESDAY_NIGHTS;
+        let (statuses, device, _) = deploy_with("window", setup);
+        assert_eq!(
+            statuses,
+            vec!["downloading", "pause_before_installing", "installing", "rebooting", "success"]
+        );
+        assert_eq!(device.borrow().active, 3);
+        assert_eq!(clock.wall(), clock_at("2020-03-04T02:00:00Z").wall());
+    }
+
+    #[test]
+    fn test_hold_reboot_for_maintenance_window() {
+        // Paused before the reboot until the window has closed
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.server.update.as_mut().unwrap().update_control_map = control_map("ArtifactReboot_Enter", "pause");
+        setup.window_conf = WEDNESDAY_NIGHTS;
+        setup.update_control =
+            UpdateControl::new(serde_json::from_str(r#"{"UpdateControlMapExpirationTimeSeconds": 86400}"#).unwrap());
+        let (clock, update_control) = (setup.clock.clone(), setup.update_control.clone());
+        let releaser = std::thread::spawn(move || {
+            while update_control.state().paused != Some(ExternalState::ArtifactReboot) {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            clock.advance(time::Duration::from_secs(90 * 60));
+            update_control.release(Action::Continue).unwrap();
+        });
+        let clock = setup.clock.clone();
+        let (statuses, device, _) = deploy_with("window-reboot", setup);
+        releaser.join().unwrap();
+        assert_eq!(
+            statuses,
+            vec!["downloading", "installing", "pause_before_rebooting", "pause_before_rebooting", "rebooting", "success"]
+        );
+        // Pointed back at the running partition while held
+        let device = device.borrow();
+        assert_eq!(device.boot_parts, vec!["3", "2", "3"]);
+        assert_eq!(device.active, 3);
+        assert_eq!(clock.wall(), clock_at("2020-03-11T02:00:00Z").wall());
+    }
+
+    #[test]
+    fn test_resume_held_deployment() {
+        let mut data = StateData::new(&update_info(), ExternalState::ArtifactInstall);
+        data.paused = true;
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.clock = clock_at("2020-03-03T12:00:00Z");
+        setup.window_conf = WEDNESDAY_NIGHTS;
+        setup.stored = Some(data);
+        let testbed = Testbed::new("held", setup);
+        let mut sm = testbed.start(FakeEvents::new(Rc::default()), &Shutdown::new());
+        assert_eq!(sm.step(), Step::Continue);
+        assert_eq!(sm.state(), ExternalState::ArtifactInstall);
+        assert!(!testbed.stored().unwrap().paused);
+        sm.step();
+        assert_eq!(sm.state(), ExternalState::ArtifactReboot);
+        assert_eq!(testbed.statuses(), vec!["pause_before_installing", "installing"]);
+    }
+
+    #[test]
+    fn test_restart_before_install() {
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.stop_in = Some(ExternalState::ArtifactInstall);
+        let (statuses, device, _) = deploy_with("restart", setup);
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "success"]);
+        assert_eq!(device.borrow().active, 3);
+    }
+
+    #[test]
+    fn test_resume_marked_install() {
+        // The install got as far as marking the update for a trial boot
+        let mut device = Device::new();
+        device.env.insert("mender_boot_part".to_string(), "3".to_string());
+        device.env.insert("upgrade_available".to_string(), "1".to_string());
+        let mut setup = Setup::new(Server::default(), device);
+        setup.stored = Some(StateData::new(&update_info(), ExternalState::ArtifactInstall));
+        let testbed = Testbed::new("marked", setup);
+        let mut sm = testbed.start(FakeEvents::new(Rc::default()), &Shutdown::new());
+        sm.step();
+        assert_eq!(sm.state(), ExternalState::ArtifactRollback);
+    }
+
+    fn control_map(state: &str, action: &str) -> Option<UpdateControlMap> {
+        serde_json::from_str(&format!(
+            r#"{{"id": "ae8b5c4e", "states": {{"{}": {{"action": "{}"}}}}}}"#,
+            state, action
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_update_control_release() {
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.server.update.as_mut().unwrap().update_control_map = control_map("ArtifactReboot_Enter", "pause");
+        let update_control = setup.update_control.clone();
+        let releaser = std::thread::spawn(move || {
+            while update_control.state().paused != Some(ExternalState::ArtifactReboot) {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            update_control.release(Action::Continue).unwrap();
+        });
+        let (statuses, device, _) = deploy_with("control-release", setup);
+        releaser.join().unwrap();
+        assert_eq!(
+            statuses,
+            vec!["downloading", "installing", "pause_before_rebooting", "rebooting", "success"]
+        );
+        assert_eq!(device.borrow().active, 3);
+    }
+
+    #[test]
+    fn test_update_control_fail_commit() {
+        // The map is persisted, and picked up again after the reboot
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.server.update.as_mut().unwrap().update_control_map = control_map("ArtifactCommit_Enter", "fail");
+        let (statuses, device, dir) = deploy_with("control-fail", setup);
+        assert_eq!(statuses, vec!["downloading", "installing", "rebooting", "failure"]);
+        let device = device.borrow();
+        assert_eq!(device.active, 2);
+        assert_eq!(device.env["upgrade_available"], "0");
+        assert!(DataStore::new(&dir).load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_leave_standalone_install() {
+        let data = StateData::standalone("release-2.mender", ExternalState::ArtifactReboot);
+        let mut setup = Setup::new(Server::default(), Device::new());
+        setup.stored = Some(data.clone());
+        let testbed = Testbed::new("standalone", setup);
+        let mut sm = testbed.start(FakeEvents::new(Rc::default()), &Shutdown::new());
+        sm.step();
+        assert_eq!(sm.state(), ExternalState::Idle);
+        assert_eq!(testbed.stored(), Some(data));
+    }
+
+    #[test]
+    fn test_no_deployment_during_standalone_install() {
+        let data = StateData::standalone("release-2", ExternalState::ArtifactReboot);
+        let mut server = Server::default();
+        server.authorized = true;
+        let mut setup = Setup::new(server, Device::new());
+        setup.stored = Some(data.clone());
+        let testbed = Testbed::new("standalone-check", setup);
+        let update = Rc::new(RefCell::new(VecDeque::from(vec![Event::CheckForUpdate])));
+        let mut sm = testbed.start(FakeEvents::new(update.clone()), &Shutdown::new());
+        for _ in 0..4 {
+            sm.step();
+        }
+        assert!(update.borrow().is_empty());
+        assert!(testbed.server.0.borrow().update.is_none());
+        assert_eq!(sm.state(), ExternalState::Idle);
+        assert!(testbed.statuses().is_empty());
+        assert_eq!(testbed.stored(), Some(data));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let testbed = Testbed::new("shutdown", Setup::new(Server::default(), Device::new()));
+        let update = Rc::new(RefCell::new(VecDeque::from(vec![Event::CheckForUpdate])));
+        let update_events = FakeEvents::new(update);
+        let update_stopped = update_events.1.clone();
+        let shutdown = Shutdown::new();
+        let mut sm = testbed.start(update_events, &shutdown);
+        while sm.state() != ExternalState::ArtifactInstall {
+            sm.step();
+        }
+        // Asked to stop once the download is done, the install is left for
+        // after the restart, see test_restart_before_install
+        shutdown.request(15);
+        // Nothing is under way at the update control pause point, so the
+        // install stops there
+        assert_eq!(sm.step(), Step::Stopped);
+        sm.run().unwrap();
+        assert_eq!(sm.state(), ExternalState::ArtifactInstall);
+        assert_eq!(testbed.statuses(), vec!["downloading"]);
+        assert_eq!(testbed.stored().unwrap().state, ExternalState::ArtifactInstall);
+        assert!(update_stopped.get());
+        assert_eq!(testbed.device.borrow().env["upgrade_available"], "0");
+    }
+}
@@ -0,0 +1,363 @@
+// transitions module holds the transition table of the state machine.
+// Every legal (state, event) pair maps to the handler which runs it, and the
+// states the handler is allowed to move on to. Anything else is an illegal
+// transition, which sends the state machine down the failure path.
+use super::{Event, ExternalState};
+
+// The kind of an Event, without the data it carries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    None,
+    Uninitialized,
+    AuthorizeAttempt,
+    CheckForUpdate,
+    SendInventory,
+    DownloadUpdate,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::None => EventKind::None,
+            Event::Uninitialized => EventKind::Uninitialized,
+            Event::AuthorizeAttempt => EventKind::AuthorizeAttempt,
+            Event::CheckForUpdate => EventKind::CheckForUpdate,
+            Event::SendInventory => EventKind::SendInventory,
+            Event::DownloadUpdate(_) => EventKind::DownloadUpdate,
+        }
+    }
+}
+
+pub const STATES: &[ExternalState] = &[
+    ExternalState::Init,
+    ExternalState::Idle,
+    ExternalState::Sync,
+    ExternalState::Download,
+    ExternalState::ArtifactInstall,
+    ExternalState::ArtifactReboot,
+    ExternalState::ArtifactCommit,
+    ExternalState::ArtifactRollback,
+    ExternalState::ArtifactRollbackReboot,
+    ExternalState::ArtifactFailure,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handler {
+    Init,
+    Idle,
+    Authorize,
+    CheckForUpdate,
+    SendInventory,
+    Download,
+    Install,
+    Reboot,
+    Commit,
+    Rollback,
+    RollbackReboot,
+    Failure,
+}
+
+#[derive(Debug)]
+pub struct Transition {
+    pub from: ExternalState,
+    pub event: EventKind,
+    pub handler: Handler,
+    pub to: &'static [ExternalState],
+}
+
+pub const TRANSITIONS: &[Transition] = &[
+    Transition {
+        from: ExternalState::Init,
+        event: EventKind::Uninitialized,
+        handler: Handler::Init,
+        to: &[
+            ExternalState::Idle,
+            ExternalState::ArtifactInstall,
+            ExternalState::ArtifactReboot,
+            ExternalState::ArtifactCommit,
+            ExternalState::ArtifactRollback,
+            ExternalState::ArtifactRollbackReboot,
+            ExternalState::ArtifactFailure,
+        ],
+    },
+    Transition {
+        from: ExternalState::Idle,
+        event: EventKind::None,
+        handler: Handler::Idle,
+        to: &[ExternalState::Idle, ExternalState::Sync],
+    },
+    Transition {
+        from: ExternalState::Sync,
+        event: EventKind::AuthorizeAttempt,
+        handler: Handler::Authorize,
+        to: &[ExternalState::Idle],
+    },
+    Transition {
+        from: ExternalState::Sync,
+        event: EventKind::CheckForUpdate,
+        handler: Handler::CheckForUpdate,
+        to: &[ExternalState::Idle, ExternalState::Download],
+    },
+    Transition {
+        from: ExternalState::Sync,
+        event: EventKind::SendInventory,
+        handler: Handler::SendInventory,
+        to: &[ExternalState::Idle],
+    },
+    Transition {
+        from: ExternalState::Download,
+        event: EventKind::DownloadUpdate,
+        handler: Handler::Download,
+        to: &[
+            ExternalState::ArtifactInstall,
+            ExternalState::ArtifactFailure,
+            ExternalState::Idle,
+        ],
+    },
+    Transition {
+        from: ExternalState::ArtifactInstall,
+        event: EventKind::None,
+        handler: Handler::Install,
+        to: &[
+            ExternalState::ArtifactReboot,
+            // Update modules which need no reboot commit straight away, and
+            // roll back a failed install
+            ExternalState::ArtifactCommit,
+            ExternalState::ArtifactRollback,
+            ExternalState::ArtifactFailure,
+            ExternalState::Idle,
+        ],
+    },
+    Transition {
+        from: ExternalState::ArtifactReboot,
+        event: EventKind::None,
+        handler: Handler::Reboot,
+        to: &[ExternalState::ArtifactRollback, ExternalState::Idle],
+    },
+    Transition {
+        from: ExternalState::ArtifactCommit,
+        event: EventKind::None,
+        handler: Handler::Commit,
+        to: &[ExternalState::Idle, ExternalState::ArtifactRollback],
+    },
+    Transition {
+        from: ExternalState::ArtifactRollback,
+        event: EventKind::None,
+        handler: Handler::Rollback,
+        to: &[ExternalState::ArtifactRollbackReboot, ExternalState::ArtifactFailure],
+    },
+    Transition {
+        from: ExternalState::ArtifactRollbackReboot,
+        event: EventKind::None,
+        handler: Handler::RollbackReboot,
+        to: &[ExternalState::ArtifactFailure],
+    },
+    Transition {
+        from: ExternalState::ArtifactFailure,
+        event: EventKind::None,
+        handler: Handler::Failure,
+        to: &[ExternalState::Idle],
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum TransitionError {
+    // There is no handler for the event in this state
+    NoTransition(ExternalState, EventKind),
+    // The handler tried to move to a state it is not allowed to
+    IllegalTarget(ExternalState, EventKind, ExternalState),
+}
+
+impl std::fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionError::NoTransition(state, event) => {
+                write!(f, "No transition from {:?} on event {:?}", state, event)
+            }
+            TransitionError::IllegalTarget(state, event, to) => write!(
+                f,
+                "Illegal transition from {:?} on event {:?} to {:?}",
+                state, event, to
+            ),
+        }
+    }
+}
+
+pub fn lookup(state: ExternalState, event: EventKind) -> Result<&'static Transition, TransitionError> {
+    TRANSITIONS
+        .iter()
+        .find(|t| t.from == state && t.event == event)
+        .ok_or(TransitionError::NoTransition(state, event))
+}
+
+impl Transition {
+    // Check that the handler moved on to one of the states it is allowed to.
+    pub fn check(&self, to: ExternalState) -> Result<ExternalState, TransitionError> {
+        if self.to.contains(&to) {
+            Ok(to)
+        } else {
+            Err(TransitionError::IllegalTarget(self.from, self.event, to))
+        }
+    }
+}
+
+// Where to go after an illegal transition in the given state: a deployment
+// which has not touched the boot environment yet fails straight away, one
+// which has is rolled back first, and outside a deployment we go back to Idle.
+pub fn failure_path(state: ExternalState) -> ExternalState {
+    match state {
+        ExternalState::Init | ExternalState::Idle | ExternalState::Sync => ExternalState::Idle,
+        ExternalState::Download
+        | ExternalState::ArtifactRollback
+        | ExternalState::ArtifactRollbackReboot => ExternalState::ArtifactFailure,
+        ExternalState::ArtifactInstall
+        | ExternalState::ArtifactReboot
+        | ExternalState::ArtifactCommit => ExternalState::ArtifactRollback,
+        ExternalState::ArtifactFailure => ExternalState::Idle,
+    }
+}
+
+// Whether moving between the two states means the first one failed: a
+// deployment going off onto the failure path. Moving along the failure path
+// is not a failure of its own.
+pub fn is_failure(from: ExternalState, to: ExternalState) -> bool {
+    let on_failure_path = |state| {
+        matches!(
+            state,
+            ExternalState::ArtifactRollback | ExternalState::ArtifactRollbackReboot | ExternalState::ArtifactFailure
+        )
+    };
+    !on_failure_path(from) && on_failure_path(to)
+}
+
+// Render the transition table as a Graphviz DOT graph. Every state is a
+// node, every legal move of a handler is an edge labelled with the event it
+// handles, and the failure paths are dashed.
+pub fn to_dot() -> String {
+    let mut dot = String::from("digraph mender {\n");
+    for state in STATES {
+        dot.push_str(&format!("    {:?};\n", state));
+    }
+    for t in TRANSITIONS {
+        for to in t.to {
+            dot.push_str(&format!("    {:?} -> {:?} [label=\"{:?}\"];\n", t.from, to, t.event));
+        }
+    }
+    for &state in STATES {
+        dot.push_str(&format!(
+            "    {:?} -> {:?} [style=dashed, label=\"failure\"];\n",
+            state,
+            failure_path(state)
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENTS: &[EventKind] = &[
+        EventKind::None,
+        EventKind::Uninitialized,
+        EventKind::AuthorizeAttempt,
+        EventKind::CheckForUpdate,
+        EventKind::SendInventory,
+        EventKind::DownloadUpdate,
+    ];
+
+    // Every legal (state, event) pair, and the handler which runs it.
+    const LEGAL: &[(ExternalState, EventKind, Handler)] = &[
+        (ExternalState::Init, EventKind::Uninitialized, Handler::Init),
+        (ExternalState::Idle, EventKind::None, Handler::Idle),
+        (ExternalState::Sync, EventKind::AuthorizeAttempt, Handler::Authorize),
+        (ExternalState::Sync, EventKind::CheckForUpdate, Handler::CheckForUpdate),
+        (ExternalState::Sync, EventKind::SendInventory, Handler::SendInventory),
+        (ExternalState::Download, EventKind::DownloadUpdate, Handler::Download),
+        (ExternalState::ArtifactInstall, EventKind::None, Handler::Install),
+        (ExternalState::ArtifactReboot, EventKind::None, Handler::Reboot),
+        (ExternalState::ArtifactCommit, EventKind::None, Handler::Commit),
+        (ExternalState::ArtifactRollback, EventKind::None, Handler::Rollback),
+        (ExternalState::ArtifactRollbackReboot, EventKind::None, Handler::RollbackReboot),
+        (ExternalState::ArtifactFailure, EventKind::None, Handler::Failure),
+    ];
+
+    #[test]
+    fn test_all_transitions() {
+        for &state in STATES {
+            for &event in EVENTS {
+                let legal = LEGAL.iter().find(|(s, e, _)| *s == state && *e == event);
+                match (lookup(state, event), legal) {
+                    (Ok(t), Some((_, _, handler))) => assert_eq!(t.handler, *handler),
+                    (Err(e), None) => assert_eq!(e, TransitionError::NoTransition(state, event)),
+                    (r, _) => panic!("Unexpected lookup of ({:?}, {:?}): {:?}", state, event, r),
+                }
+            }
+        }
+        assert_eq!(TRANSITIONS.len(), LEGAL.len());
+    }
+
+    #[test]
+    fn test_all_targets() {
+        for t in TRANSITIONS {
+            for &to in STATES {
+                match t.check(to) {
+                    Ok(s) => {
+                        assert!(t.to.contains(&to));
+                        assert_eq!(s, to);
+                    }
+                    Err(e) => {
+                        assert!(!t.to.contains(&to));
+                        assert_eq!(e, TransitionError::IllegalTarget(t.from, t.event, to));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_target_is_handled() {
+        // A handler must never move on to a state nothing can run
+        for t in TRANSITIONS {
+            for &to in t.to {
+                assert!(
+                    TRANSITIONS.iter().any(|n| n.from == to),
+                    "Nothing handles {:?}",
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_is_failure() {
+        assert!(is_failure(ExternalState::Download, ExternalState::ArtifactFailure));
+        assert!(is_failure(ExternalState::ArtifactInstall, ExternalState::ArtifactRollback));
+        assert!(is_failure(ExternalState::ArtifactCommit, ExternalState::ArtifactRollback));
+        assert!(!is_failure(ExternalState::ArtifactInstall, ExternalState::ArtifactReboot));
+        assert!(!is_failure(ExternalState::ArtifactRollback, ExternalState::ArtifactFailure));
+        assert!(!is_failure(ExternalState::ArtifactFailure, ExternalState::Idle));
+    }
+
+    #[test]
+    fn test_failure_path_is_handled() {
+        for &state in STATES {
+            let next = failure_path(state);
+            assert!(lookup(next, EventKind::None).is_ok(), "{:?} -> {:?}", state, next);
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = to_dot();
+        assert!(dot.starts_with("digraph mender {\n"));
+        for state in STATES {
+            assert!(dot.contains(&format!("    {:?};\n", state)));
+        }
+        assert!(dot.contains("    Sync -> Download [label=\"CheckForUpdate\"];\n"));
+        assert!(dot.contains("    ArtifactCommit -> ArtifactRollback [style=dashed, label=\"failure\"];\n"));
+        let edges = TRANSITIONS.iter().map(|t| t.to.len()).sum::<usize>() + STATES.len();
+        assert_eq!(dot.matches(" -> ").count(), edges);
+    }
+}
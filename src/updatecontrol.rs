@@ -0,0 +1,341 @@
+// updatecontrol module lets an external actor pause a deployment before
+// ArtifactInstall, ArtifactReboot and ArtifactCommit, and later tell it to
+// continue or fail. The pauses are set through an update control map, which
+// comes either with the update from the server, or from an application on
+// the device through the daemon socket. A map looks like
+//
+//   {"id": "01234567-89ab-cdef-0123-456789abcdef",
+//    "states": {"ArtifactReboot_Enter": {"action": "pause", "on_map_expire": "fail"}}}
+//
+// A map expires after UpdateControlMapExpirationTimeSeconds, after which a
+// paused deployment carries on with the on_map_expire action of the state,
+// or UpdateControlMapExpireAction if the state has none.
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time;
+
+use super::clock::Clock;
+use super::ExternalState;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[serde(alias = "force_continue")]
+    Continue,
+    Pause,
+    Fail,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ControlConf {
+    #[serde(rename = "UpdateControlMapExpirationTimeSeconds")]
+    expiration: u64,
+    #[serde(rename = "UpdateControlMapExpireAction")]
+    expire_action: Action,
+}
+
+impl Default for ControlConf {
+    fn default() -> Self {
+        ControlConf {
+            expiration: 3600,
+            expire_action: Action::Fail,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct StateControl {
+    #[serde(default)]
+    pub action: Option<Action>,
+    #[serde(default)]
+    pub on_map_expire: Option<Action>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UpdateControlMap {
+    pub id: String,
+    // Keyed on <State>_Enter, i.e. ArtifactInstall_Enter
+    #[serde(default)]
+    pub states: HashMap<String, StateControl>,
+}
+
+// ControlState is everything about the update control of a deployment,
+// which is persisted with the deployment across restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ControlState {
+    pub map: Option<UpdateControlMap>,
+    // When the map expires, in seconds since the epoch
+    pub expires: Option<u64>,
+    // The state the deployment is paused in
+    pub paused: Option<ExternalState>,
+}
+
+impl ControlState {
+    fn control(&self, state: ExternalState) -> Option<&StateControl> {
+        self.map
+            .as_ref()
+            .and_then(|map| map.states.get(&format!("{:?}_Enter", state)))
+    }
+
+    fn action(&self, state: ExternalState) -> Action {
+        self.control(state)
+            .and_then(|control| control.action)
+            .unwrap_or(Action::Continue)
+    }
+}
+
+fn epoch_secs(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// UpdateControl is shared between the state machine, which waits at the
+// pause points, and the control socket, which releases them.
+#[derive(Clone)]
+pub struct UpdateControl {
+    shared: Arc<(Mutex<ControlState>, Condvar)>,
+    // Set to give up on waiting, when the daemon shuts down
+    interrupted: Arc<AtomicBool>,
+    expiration: time::Duration,
+    expire_action: Action,
+}
+
+impl UpdateControl {
+    pub fn new(conf: ControlConf) -> UpdateControl {
+        UpdateControl {
+            shared: Arc::new((Mutex::new(ControlState::default()), Condvar::new())),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            expiration: time::Duration::from_secs(conf.expiration),
+            expire_action: conf.expire_action,
+        }
+    }
+
+    fn update<F: FnOnce(&mut ControlState) -> R, R>(&self, f: F) -> R {
+        let (lock, cvar) = &*self.shared;
+        let r = f(&mut lock.lock().unwrap());
+        cvar.notify_all();
+        r
+    }
+
+    pub fn state(&self) -> ControlState {
+        self.shared.0.lock().unwrap().clone()
+    }
+
+    // Pick up the update control of a deployment resumed after a restart.
+    pub fn restore(&self, state: ControlState) {
+        self.update(|s| *s = state)
+    }
+
+    pub fn clear(&self) {
+        self.update(|s| *s = ControlState::default())
+    }
+
+    pub fn set_map(&self, map: UpdateControlMap, now: time::SystemTime) {
+        info!("Using the update control map {}", map.id);
+        let expires = epoch_secs(now + self.expiration);
+        self.update(|s| {
+            s.map = Some(map);
+            s.expires = Some(expires);
+        })
+    }
+
+    // Tell the paused deployment to continue or fail.
+    pub fn release(&self, action: Action) -> Result<(), &'static str> {
+        if action == Action::Pause {
+            return Err("The deployment can only be told to continue or fail");
+        }
+        self.update(|s| {
+            let state = s.paused.ok_or("The deployment is not paused")?;
+            let map = s.map.get_or_insert_with(|| UpdateControlMap {
+                id: "local".to_string(),
+                states: HashMap::new(),
+            });
+            map.states
+                .entry(format!("{:?}_Enter", state))
+                .or_insert_with(StateControl::default)
+                .action = Some(action);
+            Ok(())
+        })
+    }
+
+    // Wake up the wait for good, leaving the deployment paused.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.update(|_| ())
+    }
+
+    // Wait at the pause point before entering the given state, until the
+    // deployment is told to continue or fail, or the map expires. on_pause
+    // is called once if the deployment has to wait. Returns Pause if the
+    // wait is interrupted.
+    pub fn wait<C: Clock, F: FnMut()>(&self, state: ExternalState, clock: &C, mut on_pause: F) -> Action {
+        let (lock, cvar) = &*self.shared;
+        let mut paused = false;
+        let mut guard = lock.lock().unwrap();
+        loop {
+            if self.interrupted.load(Ordering::SeqCst) {
+                return Action::Pause;
+            }
+            let action = match guard.action(state) {
+                Action::Pause => None,
+                action => Some(action),
+            };
+            let now = epoch_secs(clock.wall());
+            let expired = guard.expires.map_or(false, |expires| now >= expires);
+            let action = action.or_else(|| {
+                if !expired {
+                    return None;
+                }
+                let action = guard
+                    .control(state)
+                    .and_then(|control| control.on_map_expire)
+                    .unwrap_or(self.expire_action);
+                info!("The update control map has expired, doing {:?}", action);
+                Some(if action == Action::Pause { Action::Fail } else { action })
+            });
+            if let Some(action) = action {
+                guard.paused = None;
+                return action;
+            }
+            if !paused {
+                info!("Pausing the deployment before {:?}", state);
+                guard.paused = Some(state);
+                paused = true;
+                drop(guard);
+                on_pause();
+                guard = lock.lock().unwrap();
+                continue;
+            }
+            let timeout = guard
+                .expires
+                .map_or(self.expiration, |expires| time::Duration::from_secs(expires - now));
+            guard = clock.wait_until(cvar, guard, clock.now() + timeout);
+        }
+    }
+
+    // Answer the update control commands of the daemon socket:
+    //   status          the update control of the deployment, as JSON
+    //   continue, fail  release the paused deployment
+    //   set <map>       use the given update control map
+    pub fn command<C: Clock>(&self, line: &str, clock: &C) -> String {
+        let mut parts = line.trim().splitn(2, ' ');
+        let result = match (parts.next(), parts.next()) {
+            (Some("status"), None) => return serde_json::to_string(&self.state()).unwrap(),
+            (Some("continue"), None) => self.release(Action::Continue),
+            (Some("fail"), None) => self.release(Action::Fail),
+            (Some("set"), Some(map)) => match serde_json::from_str(map) {
+                Ok(map) => {
+                    self.set_map(map, clock.wall());
+                    Ok(())
+                }
+                Err(_) => Err("Invalid update control map"),
+            },
+            _ => Err("Unknown command"),
+        };
+        match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use std::thread;
+
+    fn pause_map(state: &str, on_map_expire: Option<Action>) -> UpdateControlMap {
+        let mut states = HashMap::new();
+        states.insert(
+            state.to_string(),
+            StateControl {
+                action: Some(Action::Pause),
+                on_map_expire: on_map_expire,
+            },
+        );
+        UpdateControlMap {
+            id: "ae8b5c4e-7e2b-4f0b-9b4e-6c3d2d6e1f00".to_string(),
+            states: states,
+        }
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map: UpdateControlMap = serde_json::from_str(
+            r#"{"id": "01234567", "priority": 0, "states": {
+                "ArtifactInstall_Enter": {"action": "force_continue"},
+                "ArtifactReboot_Enter": {"action": "pause", "on_map_expire": "fail", "on_action_executed": "continue"}}}"#,
+        )
+        .unwrap();
+        let state = ControlState {
+            map: Some(map),
+            expires: None,
+            paused: None,
+        };
+        assert_eq!(state.action(ExternalState::ArtifactInstall), Action::Continue);
+        assert_eq!(state.action(ExternalState::ArtifactReboot), Action::Pause);
+        assert_eq!(state.action(ExternalState::ArtifactCommit), Action::Continue);
+    }
+
+    #[test]
+    fn test_release() {
+        let control = UpdateControl::new(ControlConf::default());
+        control.set_map(pause_map("ArtifactReboot_Enter", None), time::SystemTime::now());
+        assert_eq!(control.command("continue", &SystemClock {}), "error: The deployment is not paused");
+        let releaser = control.clone();
+        let handle = thread::spawn(move || {
+            while releaser.state().paused.is_none() {
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            assert_eq!(releaser.command("fail", &SystemClock {}), "ok");
+        });
+        let mut pauses = 0;
+        let action = control.wait(ExternalState::ArtifactReboot, &SystemClock {}, || pauses += 1);
+        handle.join().unwrap();
+        assert_eq!(action, Action::Fail);
+        assert_eq!(pauses, 1);
+        assert_eq!(control.state().paused, None);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let control = UpdateControl::new(ControlConf::default());
+        control.set_map(pause_map("ArtifactInstall_Enter", None), time::SystemTime::now());
+        let interrupter = control.clone();
+        let handle = thread::spawn(move || {
+            while interrupter.state().paused.is_none() {
+                thread::sleep(time::Duration::from_millis(10));
+            }
+            interrupter.interrupt();
+        });
+        let action = control.wait(ExternalState::ArtifactInstall, &SystemClock {}, || {});
+        handle.join().unwrap();
+        assert_eq!(action, Action::Pause);
+        // Still paused, for the restart to pick up
+        assert_eq!(control.state().paused, Some(ExternalState::ArtifactInstall));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let conf = ControlConf {
+            expiration: 0,
+            expire_action: Action::Continue,
+        };
+        let control = UpdateControl::new(conf);
+        control.set_map(pause_map("ArtifactCommit_Enter", None), time::SystemTime::now());
+        assert_eq!(control.wait(ExternalState::ArtifactCommit, &SystemClock {}, || {}), Action::Continue);
+        control.set_map(
+            pause_map("ArtifactCommit_Enter", Some(Action::Fail)),
+            time::SystemTime::now(),
+        );
+        assert_eq!(control.wait(ExternalState::ArtifactCommit, &SystemClock {}, || {}), Action::Fail);
+        // Nothing to wait for in the other states
+        assert_eq!(control.wait(ExternalState::ArtifactInstall, &SystemClock {}, || {}), Action::Continue);
+    }
+}
@@ -0,0 +1,225 @@
+// windows module holds the maintenance windows, the times of the week in
+// which the client is allowed to install an update and reboot into it. The
+// download itself is not held back. Windows are configured as
+//
+//   "MaintenanceWindowTimezone": "Europe/Oslo",
+//   "MaintenanceWindows": [{"Days": ["Sat", "Sun"], "Start": "22:00", "End": "06:00"}]
+//
+// A window which ends before it starts runs past midnight, into the next
+// day, and one with no days is open every day. Without any windows the
+// client may install at any time.
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::time;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct WindowConf {
+    #[serde(rename = "MaintenanceWindowTimezone")]
+    timezone: Option<String>,
+    #[serde(rename = "MaintenanceWindows")]
+    windows: Vec<WindowEntry>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct WindowEntry {
+    #[serde(rename = "Days")]
+    days: Vec<String>,
+    #[serde(rename = "Start")]
+    start: String,
+    #[serde(rename = "End")]
+    end: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WindowError {
+    Timezone(String),
+    Day(String),
+    Time(String),
+}
+
+impl std::fmt::Display for WindowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowError::Timezone(tz) => write!(f, "Unknown timezone: {}", tz),
+            WindowError::Day(day) => write!(f, "Unknown day of the week: {}", day),
+            WindowError::Time(t) => write!(f, "Time is not on the form HH:MM: {}", t),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    fn on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, day: Weekday, t: NaiveTime) -> bool {
+        if self.start == self.end {
+            self.on(day)
+        } else if self.start < self.end {
+            self.on(day) && self.start <= t && t < self.end
+        } else {
+            (self.on(day) && self.start <= t) || (self.on(day.pred()) && t < self.end)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MaintenanceWindows {
+    tz: Tz,
+    windows: Vec<Window>,
+}
+
+impl Default for MaintenanceWindows {
+    // Always open
+    fn default() -> Self {
+        MaintenanceWindows {
+            tz: Tz::UTC,
+            windows: Vec::new(),
+        }
+    }
+}
+
+impl MaintenanceWindows {
+    pub fn from_conf(conf: WindowConf) -> Result<MaintenanceWindows, WindowError> {
+        let tz = match conf.timezone {
+            Some(tz) => tz.parse().map_err(|_| WindowError::Timezone(tz))?,
+            None => Tz::UTC,
+        };
+        let time = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| WindowError::Time(t.to_string()))
+        };
+        let mut windows = Vec::new();
+        for entry in conf.windows {
+            let mut days = Vec::new();
+            for day in &entry.days {
+                days.push(day.parse().map_err(|_| WindowError::Day(day.clone()))?);
+            }
+            windows.push(Window {
+                days: days,
+                start: time(&entry.start)?,
+                end: time(&entry.end)?,
+            });
+        }
+        Ok(MaintenanceWindows { tz: tz, windows: windows })
+    }
+
+    pub fn is_open(&self, now: time::SystemTime) -> bool {
+        self.wait_time(now).is_none()
+    }
+
+    // How long until a window opens, or None if one is open now.
+    pub fn wait_time(&self, now: time::SystemTime) -> Option<time::Duration> {
+        if self.windows.is_empty() {
+            return None;
+        }
+        let now = DateTime::<Utc>::from(now);
+        let local = now.with_timezone(&self.tz);
+        if self
+            .windows
+            .iter()
+            .any(|w| w.contains(local.weekday(), local.time()))
+        {
+            return None;
+        }
+        // Every window opens at least once a week, so look a week ahead
+        let mut next: Option<DateTime<Utc>> = None;
+        for days in 0..=7 {
+            let date = local.date_naive() + Duration::days(days);
+            for window in self.windows.iter().filter(|w| w.on(date.weekday())) {
+                let start = match self.tz.from_local_datetime(&date.and_time(window.start)).earliest() {
+                    Some(start) => start.with_timezone(&Utc),
+                    // The start time is skipped by a DST change this day
+                    None => continue,
+                };
+                if start > now && next.map_or(true, |n| start < n) {
+                    next = Some(start);
+                }
+            }
+        }
+        next.and_then(|next| (next - now).to_std().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(json: &str) -> MaintenanceWindows {
+        MaintenanceWindows::from_conf(serde_json::from_str(json).unwrap()).unwrap()
+    }
+
+    fn at(s: &str) -> time::SystemTime {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_always_open() {
+        let w = windows("{}");
+        assert!(w.is_open(at("2020-03-04T12:00:00Z")));
+    }
+
+    #[test]
+    fn test_window() {
+        // 2020-03-04 is a Wednesday
+        let w = windows(r#"{"MaintenanceWindows": [{"Days": ["Wed"], "Start": "02:00", "End": "04:00"}]}"#);
+        assert!(w.is_open(at("2020-03-04T03:00:00Z")));
+        assert!(!w.is_open(at("2020-03-04T04:00:00Z")));
+        assert_eq!(
+            w.wait_time(at("2020-03-04T01:30:00Z")),
+            Some(time::Duration::from_secs(30 * 60))
+        );
+        assert_eq!(
+            w.wait_time(at("2020-03-04T05:00:00Z")),
+            Some(time::Duration::from_secs((7 * 24 - 3) * 3600))
+        );
+    }
+
+    #[test]
+    fn test_window_past_midnight() {
+        let w = windows(r#"{"MaintenanceWindows": [{"Days": ["Sat"], "Start": "22:00", "End": "06:00"}]}"#);
+        // Saturday night, and early Sunday morning
+        assert!(w.is_open(at("2020-03-07T23:00:00Z")));
+        assert!(w.is_open(at("2020-03-08T05:59:00Z")));
+        assert!(!w.is_open(at("2020-03-08T06:00:00Z")));
+        assert!(!w.is_open(at("2020-03-07T05:00:00Z")));
+    }
+
+    #[test]
+    fn test_timezone() {
+        let w = windows(
+            r#"{"MaintenanceWindowTimezone": "Europe/Oslo",
+                "MaintenanceWindows": [{"Start": "02:00", "End": "04:00"}]}"#,
+        );
+        // 02:30 in Oslo is 01:30 UTC in the winter
+        assert!(w.is_open(at("2020-03-04T01:30:00Z")));
+        assert!(!w.is_open(at("2020-03-04T03:30:00Z")));
+    }
+
+    #[test]
+    fn test_invalid_conf() {
+        let conf = |json: &str| MaintenanceWindows::from_conf(serde_json::from_str(json).unwrap());
+        assert_eq!(
+            conf(r#"{"MaintenanceWindowTimezone": "Mars/Olympus"}"#).unwrap_err(),
+            WindowError::Timezone("Mars/Olympus".to_string())
+        );
+        assert_eq!(
+            conf(r#"{"MaintenanceWindows": [{"Days": ["Caturday"], "Start": "02:00", "End": "04:00"}]}"#)
+                .unwrap_err(),
+            WindowError::Day("Caturday".to_string())
+        );
+        assert_eq!(
+            conf(r#"{"MaintenanceWindows": [{"Start": "2am", "End": "04:00"}]}"#).unwrap_err(),
+            WindowError::Time("2am".to_string())
+        );
+    }
+}